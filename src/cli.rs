@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use clap::{builder::PossibleValuesParser, Parser, Subcommand};
+
+use crate::{registry, state::State};
+
+/// Incremental backups of Google Drive and Trello boards
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, default_value = "./config.yml")]
+    pub config: PathBuf,
+    /// Path to the synchronization state file [default: state.json next to the configuration]
+    #[arg(long, global = true)]
+    pub state: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Path of the state file, given by `--state` or kept next to the config
+    pub fn state_path(&self) -> PathBuf {
+        self.state
+            .clone()
+            .unwrap_or_else(|| State::path_for(&self.config))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the backups on their schedules, until stopped (default)
+    ///
+    /// The configuration is reloaded on SIGHUP. On SIGTERM or SIGINT, the running backups
+    /// are given the shutdown timeout to finish, and the program exits.
+    Run {
+        /// Also reload the configuration, when the file is modified
+        #[arg(long)]
+        watch_config: bool,
+    },
+    /// Back every enabled source up once, then exit
+    Once {
+        /// Back only the source of this name up
+        #[arg(long)]
+        source: Option<String>,
+    },
+    /// Write a commented configuration template, with every source disabled
+    Init {
+        /// Enable a source of this type in the template, may be repeated
+        #[arg(long = "source", value_parser = source_kinds())]
+        sources: Vec<String>,
+        /// Overwrite the configuration file, if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check, whether the configuration file can be loaded
+    CheckConfig,
+    /// List the snapshots of every configured source
+    ListSnapshots,
+    /// Show the synchronization state of every configured source
+    Status,
+}
+
+/// Accepts the types of the registered sources
+fn source_kinds() -> PossibleValuesParser {
+    PossibleValuesParser::new(
+        registry::source_types()
+            .into_iter()
+            .map(|source_type| source_type.kind),
+    )
+}
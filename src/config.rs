@@ -1,22 +1,20 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
 use flexi_logger::Duplicate;
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
 
-pub fn init() -> Config {
-    // READING CONFIG FILE SOURCE
-    std::fs::read_to_string("./config.yml")
-        // IF FAILED TO READ CONFIG -- GENERATE A DEFAULT ONE AND PUT IT TO THE FILE
-        .map_err(|_| {
-            log::error!("Failed to read the configuration file.");
-            log::info!("Generating new dummy configuration file. Please, fill it up.");
-            Config::default().write();
-        })
-        // ELSE -- PARSE CONFIG FILE
-        .and_then(|file_src| {
-            serde_yaml::from_str(file_src.as_str())
-                .map_err(|err| log::error!("Failed to parse config file: {}", err))
-        })
-        .unwrap()
-}
+use crate::{
+    error::{Error, Result},
+    registry,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub enum LogLevel {
@@ -27,9 +25,9 @@ pub enum LogLevel {
     Trace,
 }
 
-impl Into<Duplicate> for LogLevel {
-    fn into(self) -> Duplicate {
-        match self {
+impl From<LogLevel> for Duplicate {
+    fn from(level: LogLevel) -> Self {
+        match level {
             LogLevel::Info => Duplicate::Info,
             LogLevel::Debug => Duplicate::Debug,
             LogLevel::Warn => Duplicate::Warn,
@@ -42,23 +40,734 @@ impl Into<Duplicate> for LogLevel {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     pub cmd_log_level: LogLevel,
-    pub google_drive: Option<GoogleDriveConfig>,
-    pub trello: Option<TrelloConfig>,
+    /// Retry policy of the API calls, shared by all the sources
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// What to do on startup about the scheduled runs missed while the program was down
+    #[serde(default)]
+    pub catch_up: CatchUpPolicy,
+    /// What to do with a scheduled run, due while the previous one is still running
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    /// Minimum time between the starts of two runs of a source, in seconds
+    #[serde(default)]
+    pub min_interval_secs: u64,
+    /// Time zone of the cron schedules: an IANA name, e.g. `Europe/Moscow`, or `local`; UTC if unset
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Upper bound of the random delay, added to every scheduled call, in seconds
+    #[serde(default)]
+    pub jitter_secs: u64,
+    /// Periods of the day, during which the scheduled calls are deferred until the period ends
+    #[serde(default)]
+    pub blackouts: Vec<Blackout>,
+    /// Time given to the running backups to finish on SIGTERM or SIGINT, before they are cancelled
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Named instances of the sources, each with its own settings and state
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub sources: Vec<SourceConfig>,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+/// Reads an absent or empty list, such as a bare `sources:`, as an empty one
+fn null_as_empty<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::<Vec<T>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Named instance of a source
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SourceConfig {
+    /// Unique name of the instance, under which its state is kept
+    pub name: String,
+    /// Overrides the global `catch_up` policy for this instance
+    #[serde(default)]
+    pub catch_up: Option<CatchUpPolicy>,
+    /// Overrides the global `overlap` policy for this instance
+    #[serde(default)]
+    pub overlap: Option<OverlapPolicy>,
+    /// Overrides the global `min_interval_secs` for this instance
+    #[serde(default)]
+    pub min_interval_secs: Option<u64>,
+    /// Overrides the global `timezone` for this instance
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Overrides the global `jitter_secs` for this instance
+    #[serde(default)]
+    pub jitter_secs: Option<u64>,
+    /// Replaces the global `blackouts` for this instance
+    #[serde(default)]
+    pub blackouts: Option<Vec<Blackout>>,
+    #[serde(flatten)]
+    pub settings: SourceSettings,
+}
+
+/// What to do on startup about the scheduled runs, missed while the program was down
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUpPolicy {
+    /// Run immediately once, if at least one run was missed
+    #[default]
+    RunOnce,
+    /// Wait for the next scheduled run
+    Skip,
+    /// Always run immediately on startup
+    RunOnStart,
+}
+
+/// What to do with a scheduled run, due while the previous one is still running
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// Drop the run
+    #[default]
+    Skip,
+    /// Start the run as soon as the previous one finishes, keeping at most one run queued
+    QueueOne,
+    /// Cancel the previous run and start a new one
+    CancelAndRestart,
+}
+
+/// Time zone, in which the cron schedules are interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timezone {
+    /// Time zone of the host
+    Local,
+    Named(Tz),
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Self::Named(Tz::UTC)
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+        if name.eq_ignore_ascii_case("local") {
+            Ok(Self::Local)
+        } else {
+            name.parse().map(Self::Named)
+        }
+    }
+}
+
+/// Period of the day, during which no backup is started
+///
+/// The times are `HH:MM` on the wall clock of the schedule time zone. The period may span midnight,
+/// in which case `days` refer to the day it starts on. An empty `days` list means every day.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Blackout {
+    pub start: String,
+    pub end: String,
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+impl Blackout {
+    /// End of the period, if `wall_clock` falls into it
+    ///
+    /// Panics if the period is invalid, which is ruled out by [`Config::validate`].
+    pub fn end_after(&self, wall_clock: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = parse_time(&self.start).unwrap();
+        let end = parse_time(&self.end).unwrap();
+        let on = |date: NaiveDate| {
+            self.days.is_empty()
+                || self
+                    .days
+                    .iter()
+                    .any(|day| Weekday::from_str(day).unwrap() == date.weekday())
+        };
+        let (date, time) = (wall_clock.date(), wall_clock.time());
+        if start < end {
+            (start <= time && time < end && on(date)).then(|| date.and_time(end))
+        } else if start <= time && on(date) {
+            date.succ_opt().map(|next_day| next_day.and_time(end))
+        } else {
+            (time < end && date.pred_opt().is_some_and(on)).then(|| date.and_time(end))
+        }
+    }
+}
+
+fn parse_time(time: &str) -> std::result::Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| format!("invalid time `{}`, expected `HH:MM`", time))
+}
+
+/// When exactly the scheduled calls of a source are made, besides its cron schedule
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timing {
+    pub timezone: Timezone,
+    /// Upper bound of the random delay, added to every call
+    pub jitter: Duration,
+    pub blackouts: Vec<Blackout>,
 }
 
+/// Settings of a source instance, tagged with its `type`
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceSettings {
+    GoogleDrive(GoogleDriveConfig),
+    Trello(TrelloConfig),
+}
+
+/// Prefix of the environment variables, which override the config keys
+///
+/// The rest of the variable name is the path to the key, split by `__`, where the sources
+/// are addressed by name, e.g. `AUTOBACKUP__SOURCES__MY_BOARDS__API_KEY` overrides
+/// `api_key` of the source `my-boards`.
+static ENV_PREFIX: &str = "AUTOBACKUP__";
+
 impl Config {
-    /// Writes this config to `./config.yml`
-    pub fn write(&self) {
-        std::fs::write("./config.yml", serde_yaml::to_string(self).unwrap()).unwrap();
+    /// Reads and parses the config at `path`
+    ///
+    /// The legacy sections are turned into sources first. Then the environment overrides
+    /// are applied on top of the file, after which the secrets are resolved:
+    /// a `<field>_file` key replaces the field with the contents of the file,
+    /// and a `${VARIABLE}` value is replaced with the value of the environment variable.
+    pub fn read(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path).map_err(|err| Error::filesystem(path, err))?;
+        let mut value: Value = serde_yaml::from_str(&source).map_err(|err| {
+            Error::Config(format!("failed to parse `{}`: {}", path.display(), err))
+        })?;
+        migrate_legacy_sections(&mut value);
+        apply_env_overrides(&mut value, std::env::vars());
+        resolve_secrets(&mut value)?;
+        serde_yaml::from_value(value)
+            .map_err(|err| Error::Config(format!("failed to parse `{}`: {}", path.display(), err)))
+    }
+
+    /// Named instance `name` of any source
+    pub fn source(&self, name: &str) -> Option<&SourceConfig> {
+        self.sources.iter().find(|source| source.name == name)
     }
+
+    /// Catch-up policy of the source `name`
+    pub fn catch_up(&self, name: &str) -> CatchUpPolicy {
+        self.source(name)
+            .and_then(|source| source.catch_up)
+            .unwrap_or(self.catch_up)
+    }
+
+    /// Overlap policy of the source `name`
+    pub fn overlap(&self, name: &str) -> OverlapPolicy {
+        self.source(name)
+            .and_then(|source| source.overlap)
+            .unwrap_or(self.overlap)
+    }
+
+    /// Minimum time between the starts of two runs of the source `name`
+    pub fn min_interval(&self, name: &str) -> Duration {
+        Duration::from_secs(
+            self.source(name)
+                .and_then(|source| source.min_interval_secs)
+                .unwrap_or(self.min_interval_secs),
+        )
+    }
+
+    /// Time zone of the cron schedule of the source `name`
+    ///
+    /// Panics if the time zone is invalid, which is ruled out by [`Config::validate`].
+    pub fn timezone(&self, name: &str) -> Timezone {
+        self.source(name)
+            .and_then(|source| source.timezone.as_deref())
+            .or(self.timezone.as_deref())
+            .map(|timezone| timezone.parse().unwrap())
+            .unwrap_or_default()
+    }
+
+    /// Time zone, jitter and blackout periods of the schedule of the source `name`
+    pub fn timing(&self, name: &str) -> Timing {
+        let source = self.source(name);
+        Timing {
+            timezone: self.timezone(name),
+            jitter: Duration::from_secs(
+                source
+                    .and_then(|source| source.jitter_secs)
+                    .unwrap_or(self.jitter_secs),
+            ),
+            blackouts: source
+                .and_then(|source| source.blackouts.clone())
+                .unwrap_or_else(|| self.blackouts.clone()),
+        }
+    }
+
+    /// Time given to the running backups to finish on shutdown
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    /// Checks the values, which cannot be checked by parsing alone
+    ///
+    /// Returns every problem found, so they can all be fixed at once.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        let mut problem = |field: &str, message: String| {
+            problems.push(ConfigProblem {
+                field: field.into(),
+                message,
+            })
+        };
+
+        // RETRY POLICY
+        if self.retry.max_attempts == 0 {
+            problem("retry.max_attempts", "must be at least 1".into());
+        }
+        if self.retry.base_delay_ms > self.retry.max_delay_ms {
+            problem(
+                "retry.base_delay_ms",
+                "must not exceed `retry.max_delay_ms`".into(),
+            );
+        }
+
+        if let Some(timezone) = &self.timezone {
+            if let Err(message) = check_timezone(timezone) {
+                problem("timezone", message);
+            }
+        }
+
+        check_blackouts(&self.blackouts, "blackouts", &mut problem);
+
+        // SOURCES
+        let mut names = HashSet::new();
+        let mut prefixes = HashMap::new();
+        for source in &self.sources {
+            let section = format!("sources[{}]", source.name);
+            let field = |name: &str| format!("{}.{}", section, name);
+            let unique = names.insert(&source.name);
+            if source.name.trim().is_empty() {
+                problem(&field("name"), "must not be empty".into());
+            } else if !unique {
+                problem(&field("name"), "is used by another source".into());
+            }
+            if let Some(timezone) = &source.timezone {
+                if let Err(message) = check_timezone(timezone) {
+                    problem(&field("timezone"), message);
+                }
+            }
+            if let Some(blackouts) = &source.blackouts {
+                check_blackouts(blackouts, &field("blackouts"), &mut problem);
+            }
+            // THE SOURCES ARE LOOKED UP BY NAME, SO A DUPLICATE ONE CANNOT BE TOLD FROM THE FIRST
+            if !unique {
+                continue;
+            }
+            let (source_type, section) = match registry::source_type_of(self, &source.name)
+                .and_then(|source_type| {
+                    (source_type.section)(self, &source.name).map(|section| (source_type, section))
+                }) {
+                Some(found) => found,
+                None => continue,
+            };
+            if let Err(message) = check_prefix(&section.prefix) {
+                problem(&field("prefix"), message);
+            }
+            if let Some(other) = prefixes.insert(section.prefix, &source.name) {
+                problem(
+                    &field("prefix"),
+                    format!("is shared with the source `{}`", other),
+                );
+            }
+            if let Err(message) = check_cron(&section.backup_cron) {
+                problem(&field("backup_cron"), message);
+            }
+            (source_type.validate)(self, &source.name, &mut |name, message| {
+                problem(&field(name), message)
+            });
+        }
+
+        problems
+    }
+
+    /// Renders a commented config, with the default values filled in
+    ///
+    /// `sources` are the template entries of the source types, along with whether they are
+    /// enabled. The ones, which are not, are left commented out.
+    pub fn template(sources: &[(bool, String)]) -> String {
+        let retry = RetryConfig::default();
+        let section = |enabled: bool, body: String| {
+            if enabled {
+                body
+            } else {
+                body.lines().map(|line| format!("#{}\n", line)).collect()
+            }
+        };
+
+        let mut template = format!(
+            "\
+# Verbosity of the console output: Error, Warn, Info, Debug or Trace
+cmd_log_level: Info
+
+# Retry policy of the API calls, shared by all the sources
+retry:
+  max_attempts: {}
+  base_delay_ms: {}
+  max_delay_ms: {}
+  jitter_ms: {}
+
+# What to do on startup about the scheduled runs, missed while the program was down:
+# run_once (if any was missed), skip or run_on_start (always).
+# May be overridden by `catch_up` of a source.
+catch_up: run_once
+
+# What to do with a scheduled run, due while the previous one is still running:
+# skip it, queue_one (run it right after) or cancel_and_restart.
+# May be overridden by `overlap` of a source.
+overlap: skip
+
+# Minimum time between the starts of two runs of a source, in seconds; 0 disables the guard.
+# May be overridden by `min_interval_secs` of a source.
+min_interval_secs: 0
+
+# Time zone of the cron schedules: an IANA name, e.g. Europe/Moscow, or local for the host one.
+# The schedules are interpreted in UTC if unset. May be overridden by `timezone` of a source.
+# timezone: Europe/Moscow
+
+# Upper bound of the random delay, added to every scheduled backup, in seconds,
+# so that several instances do not hit the APIs at the same instant.
+# May be overridden by `jitter_secs` of a source.
+jitter_secs: 0
+
+# Periods of the day (on the wall clock of `timezone`), during which the backups are
+# deferred until the period ends. `days` may be omitted to cover every day.
+# May be replaced by `blackouts` of a source.
+# blackouts:
+#  - start: \"09:00\"
+#    end: \"18:00\"
+#    days: [Mon, Tue, Wed, Thu, Fri]
+
+# Time given to the running backups to finish on SIGTERM or SIGINT, in seconds.
+# The backups still running after it are cancelled, and their incomplete snapshots discarded.
+shutdown_timeout_secs: {}
+
+# Named instances of the sources, each with its own credentials, schedule, prefix and state.
+# Any number of instances of the same type may be listed.
+sources:
+",
+            retry.max_attempts,
+            retry.base_delay_ms,
+            retry.max_delay_ms,
+            retry.jitter_ms,
+            default_shutdown_timeout_secs(),
+        );
+        template += &sources
+            .iter()
+            .map(|(enabled, entry)| section(*enabled, entry.clone()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        template
+    }
+}
+
+/// A value of the config, which is invalid
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    /// Path to the value, e.g. `sources[my-drive].backup_cron`
+    pub field: String,
+    pub message: String,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}`: {}", self.field, self.message)
+    }
+}
+
+/// Turns the legacy top-level source sections into the sources, named after the section
+///
+/// Before `sources`, the only instance of a source type was configured in the section
+/// named after its `type`.
+fn migrate_legacy_sections(config: &mut Value) {
+    let mapping = match config.as_mapping_mut() {
+        Some(mapping) => mapping,
+        None => return,
+    };
+    for section in registry::source_types()
+        .iter()
+        .map(|source_type| source_type.kind)
+    {
+        // A DISABLED SECTION IS SET TO `~`
+        let settings = match mapping.remove(&Value::String(String::from(section))) {
+            Some(Value::Mapping(settings)) => settings,
+            _ => continue,
+        };
+        if settings.contains_key(&Value::String("prev_update_time".into())) {
+            log::warn!(
+                "`{}.prev_update_time` is no longer used, the sync state is kept in `state.json`; \
+                 the next run backs the whole source up into a new snapshot",
+                section
+            );
+        }
+        let mut source = Mapping::new();
+        source.insert("name".into(), Value::String(String::from(section)));
+        source.insert("type".into(), Value::String(String::from(section)));
+        for (key, value) in settings {
+            source.insert(key, value);
+        }
+
+        let sources_key = Value::String("sources".into());
+        if !matches!(mapping.get(&sources_key), Some(Value::Sequence(_))) {
+            mapping.insert(sources_key.clone(), Value::Sequence(Vec::new()));
+        }
+        if let Some(Value::Sequence(sources)) = mapping.get_mut(&sources_key) {
+            sources.push(Value::Mapping(source));
+        }
+    }
+}
+
+/// Sets the keys, named by the `AUTOBACKUP__*` variables among `vars`
+fn apply_env_overrides(config: &mut Value, vars: impl Iterator<Item = (String, String)>) {
+    'vars: for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if !path.is_empty() => path.to_lowercase(),
+            _ => continue,
+        };
+        // THE SOURCES ARE ONLY OVERRIDDEN, SO WITHOUT ANY LISTED, THERE IS NONE TO ADDRESS
+        if path.starts_with("sources__") && !config.get("sources").is_some_and(Value::is_sequence) {
+            continue;
+        }
+        let mut node = &mut *config;
+        for key in path.split("__") {
+            node = match node {
+                // SOURCES ARE ADDRESSED BY NAME, WITH `-` SPELLED AS `_`
+                Value::Sequence(items) => match items.iter_mut().find(|item| {
+                    item.get("name")
+                        .and_then(|name| name.as_str())
+                        .map(|name| name.to_lowercase().replace('-', "_") == key)
+                        .unwrap_or(false)
+                }) {
+                    Some(item) => item,
+                    None => continue 'vars,
+                },
+                // MISSING AND DISABLED SECTIONS ARE CREATED
+                _ => {
+                    if !node.is_mapping() {
+                        *node = Value::Mapping(Mapping::new());
+                    }
+                    let mapping = node.as_mapping_mut().unwrap();
+                    let key = Value::String(key.into());
+                    if !mapping.contains_key(&key) {
+                        mapping.insert(key.clone(), Value::Null);
+                    }
+                    mapping.get_mut(&key).unwrap()
+                }
+            };
+        }
+        // STRINGS AND SECRETS STAY STRINGS, SO THAT E.G. A NUMERIC TOKEN IS NOT TURNED INTO A NUMBER
+        let field = path.rsplit("__").next().unwrap_or_default();
+        let is_secret = registry::source_types().iter().any(|source_type| {
+            source_type
+                .secret_fields
+                .contains(&field.strip_suffix("_file").unwrap_or(field))
+        });
+        *node = match node {
+            Value::String(_) => Value::String(raw),
+            _ if is_secret => Value::String(raw),
+            _ => serde_yaml::from_str(&raw).unwrap_or(Value::String(raw)),
+        };
+        log::debug!(
+            "Config key `{}` is overridden from `{}`",
+            path.replace("__", "."),
+            name
+        );
+    }
+}
+
+/// Replaces the secret fields, given as a `*_file` path or a `${VARIABLE}` reference
+fn resolve_secrets(config: &mut Value) -> Result<()> {
+    let sources = match config
+        .get_mut("sources")
+        .and_then(|sources| sources.as_sequence_mut())
+    {
+        Some(sources) => sources,
+        None => return Ok(()),
+    };
+    for source in sources {
+        let name = source.get("name").and_then(|name| name.as_str());
+        let section = format!("sources[{}]", name.unwrap_or_default());
+        let kind = source
+            .get("type")
+            .and_then(|kind| kind.as_str())
+            .map(String::from);
+        let mapping = match source.as_mapping_mut() {
+            Some(mapping) => mapping,
+            None => continue,
+        };
+        let secret_fields = registry::source_types()
+            .into_iter()
+            .find(|source_type| kind.as_deref() == Some(source_type.kind))
+            .map(|source_type| source_type.secret_fields)
+            .unwrap_or_default();
+        for field in secret_fields {
+            let key = Value::String(String::from(*field));
+            let file_key = Value::String(format!("{}_file", field));
+
+            // SECRET FILE
+            if let Some(file) = mapping.remove(&file_key) {
+                let file = file.as_str().ok_or_else(|| {
+                    Error::Config(format!("`{}.{}_file` must be a path", section, field))
+                })?;
+                let secret =
+                    std::fs::read_to_string(file).map_err(|err| Error::filesystem(file, err))?;
+                mapping.insert(key.clone(), Value::String(secret.trim_end().into()));
+            }
+
+            // ENVIRONMENT VARIABLE REFERENCE
+            let reference = mapping
+                .get(&key)
+                .and_then(|value| value.as_str())
+                .and_then(|value| value.strip_prefix("${"))
+                .and_then(|value| value.strip_suffix('}'))
+                .map(String::from);
+            if let Some(variable) = reference {
+                let secret = std::env::var(&variable).map_err(|_| {
+                    Error::Config(format!(
+                        "`{}.{}` refers to the unset environment variable `{}`",
+                        section, field, variable
+                    ))
+                })?;
+                mapping.insert(key, Value::String(secret));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_cron(expression: &str) -> std::result::Result<(), String> {
+    let schedule = cron::Schedule::from_str(expression)
+        .map_err(|err| format!("invalid cron expression `{}`: {}", expression, err))?;
+    match schedule.upcoming(chrono::Utc).next() {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "cron expression `{}` has no upcoming calls",
+            expression
+        )),
+    }
+}
+
+fn check_timezone(name: &str) -> std::result::Result<(), String> {
+    Timezone::from_str(name).map(|_| ()).map_err(|_| {
+        format!(
+            "unknown time zone `{}`, expected an IANA name or `local`",
+            name
+        )
+    })
+}
+
+fn check_blackouts(blackouts: &[Blackout], field: &str, problem: &mut impl FnMut(&str, String)) {
+    for (index, blackout) in blackouts.iter().enumerate() {
+        let field = |name: &str| format!("{}[{}].{}", field, index, name);
+        let start = parse_time(&blackout.start);
+        let end = parse_time(&blackout.end);
+        if let Err(message) = &start {
+            problem(&field("start"), message.clone());
+        }
+        if let Err(message) = &end {
+            problem(&field("end"), message.clone());
+        }
+        if matches!((start, end), (Ok(start), Ok(end)) if start == end) {
+            problem(&field("end"), "must differ from `start`".into());
+        }
+        for day in &blackout.days {
+            if Weekday::from_str(day).is_err() {
+                problem(
+                    &field("days"),
+                    format!("unknown day `{}`, expected e.g. `Mon` or `Monday`", day),
+                );
+            }
+        }
+    }
+}
+
+/// Rejects empty credentials, and the ones left as generated by `Default`
+pub fn check_credential(value: &str, placeholder: &str) -> std::result::Result<(), String> {
+    if value.trim().is_empty() {
+        Err("must not be empty".into())
+    } else if value == placeholder {
+        Err(format!("`{}` is a placeholder, fill it up", value))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks, that the backups can be written under the `prefix`
+///
+/// A missing prefix is fine, as long as it can be created in an existing directory.
+fn check_prefix(prefix: &str) -> std::result::Result<(), String> {
+    let path = Path::new(prefix);
+    let dir = if path.exists() {
+        if !path.is_dir() {
+            return Err(format!("`{}` is not a directory", prefix));
+        }
+        path
+    } else {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !parent.is_dir() {
+            return Err(format!(
+                "neither `{}` nor its parent directory `{}` exist",
+                prefix,
+                parent.display()
+            ));
+        }
+        parent
+    };
+
+    // PROBING WRITE ACCESS
+    let probe = dir.join(".write_test");
+    std::fs::File::create(&probe)
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|err| format!("`{}` is not writable: {}", dir.display(), err))
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             cmd_log_level: LogLevel::Info,
-            google_drive: Some(Default::default()),
-            trello: Some(Default::default()),
+            retry: Default::default(),
+            catch_up: Default::default(),
+            overlap: Default::default(),
+            min_interval_secs: 0,
+            timezone: None,
+            jitter_secs: 0,
+            blackouts: Vec::new(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled with every next one
+    pub base_delay_ms: u64,
+    /// Upper bound of the delay between the attempts
+    pub max_delay_ms: u64,
+    /// Upper bound of the random delay, added to the computed one
+    pub jitter_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            jitter_ms: 1000,
         }
     }
 }
@@ -69,17 +778,149 @@ pub struct GoogleDriveConfig {
     pub client_id: String,
     pub client_secret: String,
     pub prefix: String,
-    pub prev_update_time: Option<String>,
+    /// Number of entries requested per page, when listing files and changes
+    #[serde(default = "default_page_size")]
+    pub page_size: i32,
+    /// Number of attempts to download a file, before it is reported as failed
+    #[serde(default = "default_download_attempts")]
+    pub download_attempts: u32,
+    /// Upper bound of files downloaded simultaneously
+    #[serde(default = "default_max_concurrent_downloads")]
+    pub max_concurrent_downloads: usize,
+    /// Copies to export Google-native files to, all written side by side
+    ///
+    /// Each copy is given as a list of formats, tried in order until one succeeds.
+    #[serde(default = "default_export_formats")]
+    pub export_formats: BTreeMap<GoogleDocType, Vec<Vec<ExportFormat>>>,
+}
+
+fn default_page_size() -> i32 {
+    1000
+}
+
+fn default_download_attempts() -> u32 {
+    3
+}
+
+fn default_max_concurrent_downloads() -> usize {
+    8
+}
+
+fn default_export_formats() -> BTreeMap<GoogleDocType, Vec<Vec<ExportFormat>>> {
+    let formats = |formats: &[(&str, &str)]| {
+        formats
+            .iter()
+            .map(|(mime_type, extension)| ExportFormat {
+                mime_type: String::from(*mime_type),
+                extension: String::from(*extension),
+            })
+            .collect::<Vec<_>>()
+    };
+    let pdf = || formats(&[("application/pdf", "pdf")]);
+    BTreeMap::from([
+        (
+            GoogleDocType::Document,
+            vec![
+                // EDITABLE COPY
+                formats(&[
+                    ("application/vnd.oasis.opendocument.text", "odt"),
+                    (
+                        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                        "docx",
+                    ),
+                ]),
+                // FROZEN COPY
+                pdf(),
+            ],
+        ),
+        (
+            GoogleDocType::Spreadsheet,
+            vec![
+                // EDITABLE COPY
+                formats(&[
+                    (
+                        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                        "xlsx",
+                    ),
+                    ("application/vnd.oasis.opendocument.spreadsheet", "ods"),
+                ]),
+                // FROZEN COPY
+                pdf(),
+            ],
+        ),
+        (
+            GoogleDocType::Presentation,
+            vec![
+                // EDITABLE COPY
+                formats(&[
+                    ("application/vnd.oasis.opendocument.presentation", "odp"),
+                    (
+                        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+                        "pptx",
+                    ),
+                ]),
+                // FROZEN COPY
+                pdf(),
+            ],
+        ),
+        (
+            GoogleDocType::Drawing,
+            vec![
+                formats(&[("image/svg+xml", "svg"), ("image/png", "png")]),
+                pdf(),
+            ],
+        ),
+        (
+            GoogleDocType::AppsScript,
+            vec![formats(&[(
+                "application/vnd.google-apps.script+json",
+                "json",
+            )])],
+        ),
+    ])
+}
+
+/// Google-native file type, which can only be exported
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GoogleDocType {
+    Document,
+    Spreadsheet,
+    Presentation,
+    Drawing,
+    AppsScript,
+}
+
+impl GoogleDocType {
+    /// Recognizes the Google-native type by its MIME type
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "application/vnd.google-apps.document" => Some(Self::Document),
+            "application/vnd.google-apps.spreadsheet" => Some(Self::Spreadsheet),
+            "application/vnd.google-apps.presentation" => Some(Self::Presentation),
+            "application/vnd.google-apps.drawing" => Some(Self::Drawing),
+            "application/vnd.google-apps.script" => Some(Self::AppsScript),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ExportFormat {
+    pub mime_type: String,
+    pub extension: String,
 }
 
 impl Default for GoogleDriveConfig {
     fn default() -> Self {
         Self {
-            backup_cron: "*/30 * * * * *".into(),
+            backup_cron: "0 0 * * * *".into(),
             client_id: "put_your_client_id_here".into(),
             client_secret: "put_your_secret_here".into(),
             prefix: "./drive".into(),
-            prev_update_time: None,
+            page_size: default_page_size(),
+            download_attempts: default_download_attempts(),
+            max_concurrent_downloads: default_max_concurrent_downloads(),
+            export_formats: default_export_formats(),
         }
     }
 }
@@ -96,7 +937,7 @@ pub struct TrelloConfig {
 impl Default for TrelloConfig {
     fn default() -> Self {
         Self {
-            backup_cron: "* * */6 * * *".into(),
+            backup_cron: "0 0 */6 * * *".into(),
             board_ids: vec!["board_id0".into(), "board_id1".into(), "board_id2".into()],
             api_key: "put_your_api_key_here".into(),
             personal_token: "put_your_token_here".into(),
@@ -104,3 +945,201 @@ impl Default for TrelloConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blackout(start: &str, end: &str, days: &[&str]) -> Blackout {
+        Blackout {
+            start: start.into(),
+            end: end.into(),
+            days: days.iter().map(|day| String::from(*day)).collect(),
+        }
+    }
+
+    fn wall_clock(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%F %H:%M").unwrap()
+    }
+
+    #[test]
+    fn cron_must_have_upcoming_calls() {
+        assert!(check_cron("0 0 */6 * * *").is_ok());
+        assert!(check_cron("0 0 0 1 1 * 2020").is_err());
+        assert!(check_cron("every day").is_err());
+    }
+
+    #[test]
+    fn blackout_within_a_day_ends_on_that_day() {
+        // 2024-07-01 IS A MONDAY
+        let working_hours = blackout("09:00", "18:00", &["Mon", "Tue", "Wed", "Thu", "Fri"]);
+        assert_eq!(
+            working_hours.end_after(wall_clock("2024-07-01 09:00")),
+            Some(wall_clock("2024-07-01 18:00"))
+        );
+        assert_eq!(
+            working_hours.end_after(wall_clock("2024-07-01 08:59")),
+            None
+        );
+        assert_eq!(
+            working_hours.end_after(wall_clock("2024-07-01 18:00")),
+            None
+        );
+        assert_eq!(
+            working_hours.end_after(wall_clock("2024-07-06 12:00")),
+            None
+        );
+    }
+
+    #[test]
+    fn blackout_across_midnight_ends_on_the_next_day() {
+        let nights = blackout("22:00", "06:00", &[]);
+        assert_eq!(
+            nights.end_after(wall_clock("2024-07-01 23:00")),
+            Some(wall_clock("2024-07-02 06:00"))
+        );
+        assert_eq!(
+            nights.end_after(wall_clock("2024-07-02 03:00")),
+            Some(wall_clock("2024-07-02 06:00"))
+        );
+        assert_eq!(nights.end_after(wall_clock("2024-07-02 12:00")), None);
+    }
+
+    #[test]
+    fn blackout_across_midnight_is_limited_by_the_day_it_starts_on() {
+        // 2024-07-05 IS A FRIDAY
+        let friday_night = blackout("22:00", "06:00", &["Fri"]);
+        assert_eq!(
+            friday_night.end_after(wall_clock("2024-07-05 23:00")),
+            Some(wall_clock("2024-07-06 06:00"))
+        );
+        assert_eq!(
+            friday_night.end_after(wall_clock("2024-07-06 05:00")),
+            Some(wall_clock("2024-07-06 06:00"))
+        );
+        assert_eq!(friday_night.end_after(wall_clock("2024-07-05 05:00")), None);
+        assert_eq!(friday_night.end_after(wall_clock("2024-07-06 23:00")), None);
+    }
+
+    fn yaml(source: &str) -> Value {
+        serde_yaml::from_str(source).unwrap()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn env_overrides_set_nested_and_source_keys() {
+        let mut config = yaml(
+            "
+retry:
+  max_attempts: 3
+sources:
+  - name: my-trello
+    type: trello
+    backup_cron: \"0 0 */6 * * *\"
+",
+        );
+        apply_env_overrides(
+            &mut config,
+            vars(&[
+                ("AUTOBACKUP__RETRY__MAX_ATTEMPTS", "5"),
+                ("AUTOBACKUP__SOURCES__MY_TRELLO__BACKUP_CRON", "0 0 * * * *"),
+                ("AUTOBACKUP__SOURCES__MY_TRELLO__PERSONAL_TOKEN", "0123"),
+                ("AUTOBACKUP__SOURCES__MISSING__API_KEY", "key"),
+                ("HOME", "/root"),
+            ]),
+        );
+        assert_eq!(
+            config,
+            yaml(
+                "
+retry:
+  max_attempts: 5
+sources:
+  - name: my-trello
+    type: trello
+    backup_cron: \"0 0 * * * *\"
+    personal_token: \"0123\"
+",
+            )
+        );
+    }
+
+    #[test]
+    fn env_overrides_skip_the_sources_without_a_list() {
+        for source in ["sources:", "cmd_log_level: Info"] {
+            let mut config = yaml(source);
+            apply_env_overrides(
+                &mut config,
+                vars(&[("AUTOBACKUP__SOURCES__DRIVE__CLIENT_ID", "x")]),
+            );
+            assert_eq!(config, yaml(source));
+        }
+    }
+
+    #[test]
+    fn env_overrides_create_the_missing_sections() {
+        let mut config = yaml("cmd_log_level: Info");
+        apply_env_overrides(
+            &mut config,
+            vars(&[("AUTOBACKUP__TIMEZONE", "Europe/Berlin")]),
+        );
+        assert_eq!(
+            config,
+            yaml("{cmd_log_level: Info, timezone: Europe/Berlin}")
+        );
+    }
+
+    #[test]
+    fn secrets_are_read_from_files_and_variables() {
+        let file = std::env::temp_dir().join(format!("autobackup-secret-{}", std::process::id()));
+        std::fs::write(&file, "secret from file\n").unwrap();
+        std::env::set_var("AUTOBACKUP_TEST_TOKEN", "secret from variable");
+        let mut config = yaml(&format!(
+            "
+sources:
+  - name: trello
+    type: trello
+    api_key_file: {:?}
+    personal_token: ${{AUTOBACKUP_TEST_TOKEN}}
+",
+            file
+        ));
+        let result = resolve_secrets(&mut config);
+        std::fs::remove_file(&file).unwrap();
+        result.unwrap();
+        assert_eq!(
+            config,
+            yaml(
+                "
+sources:
+  - name: trello
+    type: trello
+    personal_token: secret from variable
+    api_key: secret from file
+",
+            )
+        );
+    }
+
+    #[test]
+    fn secrets_must_not_refer_to_unset_variables() {
+        let mut config = yaml(
+            "
+sources:
+  - name: drive
+    type: google_drive
+    client_secret: ${AUTOBACKUP_TEST_UNSET}
+",
+        );
+        assert!(matches!(
+            resolve_secrets(&mut config),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -1,25 +1,177 @@
 use crate::{
-    config::{Config, GoogleDriveConfig},
-    util::{utc_to_string, Backup, Lock},
+    config::{
+        check_credential, Config, ExportFormat, GoogleDocType, GoogleDriveConfig, RetryConfig,
+        SourceSettings,
+    },
+    error::{Error, Result},
+    report::{BackupReport, ItemOutcome},
+    state::State,
+    util::{
+        parse_retry_after, retry, utc_to_string, Backup, Failure, Lock, Retryable, SourceSection,
+    },
 };
-use chrono::{DateTime, Utc};
+use chrono::Utc;
+use futures::StreamExt;
 use google_drive3::{
-    hyper::{self, body},
+    api::File,
+    client,
+    hyper::{self, body::HttpBody},
     hyper_rustls, oauth2, DriveHub,
 };
 use indicatif::ProgressBar;
 use log::*;
-use std::{borrow::Borrow, collections::LinkedList, pin::Pin, sync::Arc};
-use tokio::sync::Mutex;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap, LinkedList},
+    fmt::Display,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+static FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+/// Reasons of 403 responses, which Drive uses to report exceeded rate limits
+static RATE_LIMIT_REASONS: &[&str] = &["userRateLimitExceeded", "rateLimitExceeded"];
+/// Common prefix of the Google-native file types, which have to be exported
+static GOOGLE_APPS_MIME_PREFIX: &str = "application/vnd.google-apps.";
+/// Id of the folder tree entry, which holds the shared drives, as no Drive id is empty
+static SHARED_DRIVES_ID: &str = "";
+/// Number of runs in a row, on which a file may fail, before it is no longer retried
+const MAX_FAILED_RUNS: u32 = 5;
 
 pub struct DriveBackup {
+    /// Name of the source in the config
+    name: String,
     config: Pin<Arc<Mutex<Config>>>,
-    hub: Pin<Arc<Mutex<DriveHub>>>,
+    state: Lock<State>,
+    hub: Pin<Arc<DriveHub>>,
+}
+
+/// Synchronization state of a Google Drive source
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DriveState {
+    pub last_sync_time: Option<String>,
+    /// Drive Changes API page token, from which the next incremental backup starts
+    pub page_token: Option<String>,
+    /// Files, which have failed to be backed up, with the number of runs they have failed on,
+    /// retried on the next run
+    #[serde(default)]
+    pub failed_files: BTreeMap<String, u32>,
+    pub last_snapshot: Option<String>,
 }
 
 #[async_trait::async_trait]
 impl Backup for DriveBackup {
-    async fn new(config: Lock<Config>) -> Self {
+    const NAME: &'static str = "Google Drive";
+    const KIND: &'static str = "google_drive";
+    const SECRET_FIELDS: &'static [&'static str] = &["client_id", "client_secret"];
+
+    fn section(config: &Config, name: &str) -> Option<SourceSection> {
+        settings(config, name).map(|drive| SourceSection {
+            backup_cron: drive.backup_cron.clone(),
+            prefix: drive.prefix.clone(),
+            credentials: vec![drive.client_id.clone(), drive.client_secret.clone()],
+        })
+    }
+
+    fn validate(config: &Config, name: &str, problem: &mut dyn FnMut(&str, String)) {
+        let drive = match settings(config, name) {
+            Some(drive) => drive,
+            None => return,
+        };
+        let placeholder = GoogleDriveConfig::default();
+        if let Err(message) = check_credential(&drive.client_id, &placeholder.client_id) {
+            problem("client_id", message);
+        }
+        if let Err(message) = check_credential(&drive.client_secret, &placeholder.client_secret) {
+            problem("client_secret", message);
+        }
+        if !(1..=1000).contains(&drive.page_size) {
+            problem("page_size", "must be between 1 and 1000".into());
+        }
+        if drive.download_attempts == 0 {
+            problem("download_attempts", "must be at least 1".into());
+        }
+        if drive.max_concurrent_downloads == 0 {
+            problem("max_concurrent_downloads", "must be at least 1".into());
+        }
+        for (doc_type, copies) in &drive.export_formats {
+            for (i, formats) in copies.iter().enumerate() {
+                if formats.is_empty() {
+                    problem(
+                        &format!("export_formats.{:?}[{}]", doc_type, i),
+                        "must list at least one format".into(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn template() -> String {
+        let defaults = GoogleDriveConfig::default();
+        format!(
+            "  - name: drive
+    type: {}
+    # Schedule of the backups: sec min hour day-of-month month day-of-week
+    backup_cron: {:?}
+    # OAuth client credentials of the application, each may also be given as
+    # a `${{VARIABLE}}` reference or read from a file with e.g. `client_secret_file`
+    client_id: {:?}
+    client_secret: {:?}
+    # Directory, where the snapshots are written
+    prefix: {:?}
+    page_size: {}
+    download_attempts: {}
+    max_concurrent_downloads: {}
+    # Copies, into which the Google-native files are exported, side by side; each copy
+    # lists the formats to try in order, until one succeeds. The defaults are:
+    # export_formats:
+{}",
+            Self::KIND,
+            defaults.backup_cron,
+            defaults.client_id,
+            defaults.client_secret,
+            defaults.prefix,
+            defaults.page_size,
+            defaults.download_attempts,
+            defaults.max_concurrent_downloads,
+            serde_yaml::to_string(&defaults.export_formats)
+                .expect("Export formats are serializable")
+                .lines()
+                .filter(|line| *line != "---")
+                .map(|line| format!("    #   {}\n", line))
+                .collect::<String>(),
+        )
+    }
+
+    fn status(state: &State, name: &str) -> Vec<String> {
+        let state = match state.source::<DriveState>(name) {
+            Ok(state) => state,
+            Err(err) => return vec![err.to_string()],
+        };
+        vec![
+            format!(
+                "last sync: {}",
+                state.last_sync_time.as_deref().unwrap_or("never")
+            ),
+            format!(
+                "last snapshot: {}",
+                state.last_snapshot.as_deref().unwrap_or("none")
+            ),
+            format!("initialized: {}", state.page_token.is_some()),
+            format!("failed files to retry: {}", state.failed_files.len()),
+        ]
+    }
+
+    async fn new(
+        name: String,
+        config: Lock<Config>,
+        state: Lock<State>,
+    ) -> Result<(Self, Option<BackupReport>)> {
         trace!("Constructing DriveBackup");
         let (client_id, client_secret) = {
             let config = config.lock().await;
@@ -27,7 +179,7 @@ impl Backup for DriveBackup {
                 client_id,
                 client_secret,
                 ..
-            } = config.borrow().google_drive.as_ref().unwrap();
+            } = settings(config.borrow(), &name).ok_or_else(|| no_source(&name))?;
             (client_id.clone(), client_secret.clone())
         };
         let secret = oauth2::ApplicationSecret {
@@ -38,15 +190,18 @@ impl Backup for DriveBackup {
             project_id: Some("vectorcirclesbackup".into()),
             ..Default::default()
         };
+        // THE CONSENT IS GIVEN ONCE, AS NOBODY ANSWERS IT UNDER A TIMER
+        let tokens = state.lock().await.source_file(&name, "tokens.json");
         let auth = oauth2::InstalledFlowAuthenticator::builder(
             secret,
             oauth2::InstalledFlowReturnMethod::HTTPRedirect,
         )
+        .persist_tokens_to_disk(tokens)
         .build()
         .await
-        .unwrap();
+        .map_err(|err| Error::Auth(err.to_string()))?;
 
-        let hub = Arc::pin(Mutex::new(DriveHub::new(
+        let hub = Arc::pin(DriveHub::new(
             hyper::Client::builder().build(
                 hyper_rustls::HttpsConnectorBuilder::new()
                     .with_native_roots()
@@ -55,337 +210,1144 @@ impl Backup for DriveBackup {
                     .build(),
             ),
             auth,
-        )));
+        ));
 
-        hub.lock()
-            .await
-            .changes()
-            .get_start_page_token()
-            .add_scope("https://www.googleapis.com/auth/drive.readonly")
-            .add_scope("https://www.googleapis.com/auth/drive.metadata.readonly")
-            .doit()
-            .await
-            .unwrap();
+        let retry_policy = config.lock().await.retry.clone();
+        let hub_ref = &hub;
+        let (_, start_page_token) = retry(&retry_policy, "get drive start page token", || async {
+            let mut delegate = RetryAfter::default();
+            let result = hub_ref
+                .changes()
+                .get_start_page_token()
+                .supports_all_drives(true)
+                .add_scope("https://www.googleapis.com/auth/drive.readonly")
+                .add_scope("https://www.googleapis.com/auth/drive.metadata.readonly")
+                .delegate(&mut delegate)
+                .doit()
+                .await;
+            delegate.attach(result)
+        })
+        .await?;
 
-        let this = Self { config, hub };
-        this.initial_backup().await;
+        let this = Self {
+            name,
+            config,
+            state,
+            hub,
+        };
+        let initial_report = this
+            .initial_backup(start_page_token.start_page_token.ok_or_else(|| {
+                Error::Api("start page token did not arrive with the response".into())
+            })?)
+            .await?;
         trace!("Constructed DriveBackup");
-        this
+        Ok((this, initial_report))
     }
 
-    async fn backup_changes(&self) {
+    async fn backup_changes(&self) -> Result<BackupReport> {
         trace!("Called DriveBackup::backup_changes");
+        let mut report = BackupReport::new(self.report_source());
         /* ---- SYSTEM STATE PROCESSING ---- */
-        let update_time = {
-            let update_time = self
-                .config
-                .lock()
-                .await
-                .google_drive
-                .as_mut()
-                .unwrap()
-                .prev_update_time
-                .replace(Utc::now().to_rfc3339())
-                .map(|x| DateTime::parse_from_rfc3339(&x).expect("Update time is invalid."))
-                .expect("The system has not been initialized.");
-            self.config.lock().await.write();
-            update_time
+        let (page_token, previously_failed) = {
+            let state = self.state.lock().await;
+            let drive_state = state.source::<DriveState>(&self.name)?;
+            (
+                drive_state.page_token.ok_or_else(|| {
+                    Error::Config("the drive backup has not been initialized".into())
+                })?,
+                drive_state.failed_files,
+            )
         };
 
-        /* ---- DOWNLOADING UPDATED FILES ---- */
-        let current_dir = format!(
-            "{}/{}",
-            self.config
-                .lock()
-                .await
-                .google_drive
-                .as_ref()
-                .unwrap()
-                .prefix,
-            utc_to_string(Utc::now())
-        );
-        let files = self
-            .hub
-            .lock()
-            .await
-            .files()
-            .list()
-            .param("fields", "*")
-            .doit()
+        /* ---- COLLECTING CHANGES ---- */
+        let (changes, new_page_token) = self.list_changes(page_token).await?;
+        debug!("Drive reported {} changed file(s)", changes.len());
+
+        // The files, which have failed on the previous runs, are retried unless changed since
+        let mut failed_files = BTreeMap::new();
+        let mut retried = Vec::new();
+        let retry_policy = self.retry_policy().await;
+        for file_id in previously_failed
+            .keys()
+            .filter(|file_id| !changes.iter().any(|change| &change.file_id == *file_id))
+        {
+            match retry(
+                &retry_policy,
+                format!("get drive file {}", file_id),
+                || async {
+                    let mut delegate = RetryAfter::default();
+                    let result = self
+                        .hub
+                        .files()
+                        .get(file_id)
+                        .supports_all_drives(true)
+                        .param("fields", "*")
+                        .delegate(&mut delegate)
+                        .doit()
+                        .await;
+                    delegate.attach(result)
+                },
+            )
             .await
-            .unwrap()
-            .1
-            .files
-            .unwrap()
-            .into_iter()
-            .filter_map(|file| {
-                let date = |s: &Option<String>| {
-                    s.as_ref()
-                        .map(AsRef::as_ref)
-                        .map(DateTime::parse_from_rfc3339)
-                        .map(Result::unwrap)
-                        .zip(Some(chrono::Duration::seconds(3)))
-                        .map(|(a, b)| a - b)
-                };
-                let modified_time = date(&file.modified_time)
-                    .expect("Modified DateTime did not arrive with the response");
-                let created_time = date(&file.created_time)
-                    .expect("Created DateTime did not arrive with the response");
-
-                (update_time <= modified_time || update_time <= created_time)
-                    .then(|| file.id.unwrap())
-                    .zip(file.name)
-            })
-            .collect::<Vec<_>>();
+            {
+                Ok((_, file)) if file.trashed.unwrap_or(false) => {}
+                Ok((_, file)) => retried.push(file),
+                Err(err) => {
+                    report.record(format!("file {}", file_id), &Err(err.into()));
+                    count_failure(&mut failed_files, &previously_failed, file_id.clone());
+                }
+            }
+        }
+        debug!("Retrying {} previously failed file(s)", retried.len());
+
+        if !changes.is_empty() || !retried.is_empty() {
+            /* ---- DOWNLOADING UPDATED FILES ---- */
+            let current_dir = format!(
+                "{}/{}",
+                self.settings(|drive| drive.prefix.clone()).await?,
+                utc_to_string(Utc::now())
+            );
+            let folders = self.folder_tree().await?;
+            let files = changes
+                .iter()
+                .filter(|change| {
+                    change.kind == ChangeKind::Modified
+                        && change.mime_type.as_deref() != Some(FOLDER_MIME_TYPE)
+                })
+                .map(|change| &change.file)
+                .chain(&retried)
+                .map(|file| {
+                    (
+                        format!(
+                            "{}{}",
+                            current_dir,
+                            folders.path(
+                                file.parents
+                                    .as_ref()
+                                    .and_then(|parents| parents.first())
+                                    .map(String::as_str)
+                            )
+                        ),
+                        file,
+                    )
+                })
+                .collect::<Vec<_>>();
 
-        if files.is_empty() {
-            return;
+            info!("Pulling drive updates");
+            let results = self.download_drive_files(files, "Update Progress").await?;
+            for (file, result) in &results {
+                report.record(describe(file), result);
+            }
+            for file_id in failed_ids(&results) {
+                count_failure(&mut failed_files, &previously_failed, file_id);
+            }
+            trace!("Finished pulling dive updates");
+
+            let written = results
+                .iter()
+                .any(|(_, result)| matches!(result, Ok(ItemOutcome::Written(_))));
+            if changes.is_empty() && !written {
+                // ONLY THE RETRIED FILES HAVE FAILED AGAIN, LEAVING THE DIRECTORIES OF THEIR FOLDERS
+                if Path::new(&current_dir).exists() {
+                    std::fs::remove_dir_all(&current_dir)
+                        .map_err(|err| Error::filesystem(&current_dir, err))?;
+                }
+            } else {
+                // Renames, moves, trashing and deletions are recorded next to the files
+                std::fs::create_dir_all(&current_dir)
+                    .map_err(|err| Error::filesystem(&current_dir, err))?;
+                let manifest_path = format!("{}/changes.json", current_dir);
+                std::fs::write(
+                    &manifest_path,
+                    serde_json::to_string_pretty(&changes)
+                        .expect("Change manifest is serializable"),
+                )
+                .map_err(|err| Error::filesystem(&manifest_path, err))?;
+                report.snapshot = Some(current_dir);
+            }
         }
+        report.finish()?;
 
-        let progress_bar = Arc::pin(Mutex::new(ProgressBar::new(files.len() as u64)));
-        info!("Pulling drive updates");
-        futures::future::join_all(files.into_iter().map(|(id, name)| {
-            let current_dir = current_dir.clone();
-            let progress_bar = progress_bar.clone();
-            async move {
-                self.download_drive_file(current_dir, id, name).await;
-                progress_bar.lock().await.inc(1);
+        /* ---- PERSISTING SYSTEM STATE ---- */
+        {
+            let mut state = self.state.lock().await;
+            let mut drive_state = state.source::<DriveState>(&self.name)?;
+            drive_state.page_token = Some(new_page_token);
+            drive_state.failed_files = failed_files;
+            drive_state.last_sync_time = Some(Utc::now().to_rfc3339());
+            if report.snapshot.is_some() {
+                drive_state.last_snapshot = report.snapshot.clone();
             }
-        }))
-        .await;
-        progress_bar.lock().await.finish();
-        trace!("Finished pulling dive updates");
+            state.set_source(&self.name, &drive_state);
+            state.write()?;
+        }
         trace!("Finished DriveBackup::backup_changes");
+        Ok(report)
     }
 }
 
+/// Integrity data of a file, as reported by Drive
+///
+/// Only binary files have one, so every field is optional.
+#[derive(Debug, Default)]
+struct Checksum {
+    size: Option<u64>,
+    md5: Option<String>,
+}
+
+impl Checksum {
+    /// Checks the written file against this checksum
+    fn verify(&self, size: u64, md5: &str) -> Result<()> {
+        let mismatch = |what, expected: &dyn std::fmt::Display, actual: &dyn std::fmt::Display| {
+            Error::Integrity(format!(
+                "{} mismatch: expected {}, got {}",
+                what, expected, actual
+            ))
+        };
+        match (self.size, self.md5.as_deref()) {
+            (Some(expected), _) if expected != size => Err(mismatch("size", &expected, &size)),
+            (_, Some(expected)) if !expected.eq_ignore_ascii_case(md5) => {
+                Err(mismatch("md5", &expected, &md5))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Error of a source, missing from the config
+/// Settings of the Google Drive source `name`
+fn settings<'a>(config: &'a Config, name: &str) -> Option<&'a GoogleDriveConfig> {
+    config
+        .sources
+        .iter()
+        .find_map(|source| match &source.settings {
+            SourceSettings::GoogleDrive(drive) if source.name == name => Some(drive),
+            _ => None,
+        })
+}
+
+fn no_source(name: &str) -> Error {
+    Error::Config(format!("there is no Google Drive source `{}`", name))
+}
+
+/// Counts another run, on which the file `file_id` has failed, into `failed_files`
+///
+/// A file, which has failed on [`MAX_FAILED_RUNS`] runs, is given up, as it will hardly ever
+/// succeed, e.g. a Doc over the export size limit.
+fn count_failure(
+    failed_files: &mut BTreeMap<String, u32>,
+    previously_failed: &BTreeMap<String, u32>,
+    file_id: String,
+) {
+    let runs = previously_failed.get(&file_id).copied().unwrap_or_default() + 1;
+    if runs < MAX_FAILED_RUNS {
+        failed_files.insert(file_id, runs);
+    } else {
+        warn!(
+            "Giving drive file {} up, it has failed on {} runs in a row",
+            file_id, runs
+        );
+    }
+}
+
+/// Ids of the files, which have failed to be backed up
+fn failed_ids<'a, T>(results: &'a [(&File, Result<T>)]) -> impl Iterator<Item = String> + 'a {
+    results
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .filter_map(|(file, _)| file.id.clone())
+}
+
+/// Names of the `files` in their destination folders
+///
+/// Drive allows several files of a folder to share a name, e.g. `Untitled document`,
+/// so the files, which do, are told apart by their ids.
+fn snapshot_names(files: &[(String, &File)]) -> Vec<String> {
+    let name = |file: &File| {
+        file.name
+            .clone()
+            .unwrap_or_else(|| file.id.clone().unwrap_or_default())
+    };
+    let mut counts = HashMap::<(&str, String), usize>::new();
+    for (folder, file) in files {
+        *counts.entry((folder.as_str(), name(file))).or_default() += 1;
+    }
+    files
+        .iter()
+        .map(|(folder, file)| {
+            let name = name(file);
+            if counts[&(folder.as_str(), name.clone())] == 1 {
+                return name;
+            }
+            let id = file.id.as_deref().unwrap_or_default();
+            match name.rfind('.') {
+                Some(dot) if dot > 0 => format!("{}-{}{}", &name[..dot], id, &name[dot..]),
+                _ => format!("{}-{}", name, id),
+            }
+        })
+        .collect()
+}
+
+/// Human-readable identification of a file in the backup report
+fn describe(file: &File) -> String {
+    format!(
+        "{} ({})",
+        file.name.as_deref().unwrap_or_default(),
+        file.id.as_deref().unwrap_or_default()
+    )
+}
+
+/// Streams the response body of the file `file_id` to `path`, chunk by chunk
+///
+/// The body is written to a temporary `.part` file, which is renamed to `path` only
+/// once complete and matching the `expected` checksum, so partial or corrupted files
+/// never appear in the snapshot. The temporary file is named after the id as well,
+/// as the concurrent downloads may share a name. Returns the number of bytes written.
+async fn write_response(
+    path: impl AsRef<Path>,
+    file_id: &str,
+    response: hyper::Response<hyper::Body>,
+    expected: &Checksum,
+) -> Result<u64> {
+    let path = path.as_ref();
+    let part_path = {
+        let mut part_path = path.as_os_str().to_owned();
+        part_path.push(format!(".{}.part", file_id));
+        PathBuf::from(part_path)
+    };
+
+    let result = async {
+        let fs_error = |err| Error::filesystem(&part_path, err);
+        let mut file = tokio::fs::File::create(&part_path)
+            .await
+            .map_err(fs_error)?;
+        let mut body = response.into_body();
+        let mut hasher = Md5::new();
+        let mut bytes = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|err| Error::Network(err.to_string()))?;
+            file.write_all(&chunk).await.map_err(fs_error)?;
+            hasher.update(&chunk);
+            bytes += chunk.len() as u64;
+        }
+        file.flush().await.map_err(fs_error)?;
+        file.sync_all().await.map_err(fs_error)?;
+        expected.verify(bytes, &format!("{:x}", hasher.finalize()))?;
+        Ok(bytes)
+    }
+    .await;
+
+    match result {
+        Ok(bytes) => tokio::fs::rename(&part_path, path)
+            .await
+            .map(|_| bytes)
+            .map_err(|err| Error::filesystem(path, err)),
+        Err(err) => {
+            // Best effort, the original error is more important
+            let _ = tokio::fs::remove_file(&part_path).await;
+            Err(err)
+        }
+    }
+}
+
+/// Failed Drive API call, along with the delay Drive has asked for before the next attempt
+struct DriveError {
+    error: Box<client::Error>,
+    retry_after: Option<Duration>,
+}
+
+impl Display for DriveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl From<DriveError> for Error {
+    fn from(err: DriveError) -> Self {
+        (*err.error).into()
+    }
+}
+
+impl Retryable for DriveError {
+    fn classify(&self) -> Failure {
+        let is_transient = |code: u64, reasons: &[&str]| {
+            code == 429
+                || code >= 500
+                || (code == 403
+                    && reasons
+                        .iter()
+                        .any(|reason| RATE_LIMIT_REASONS.contains(reason)))
+        };
+        match self.error.as_ref() {
+            client::Error::HttpError(_) | client::Error::Io(_) => Failure::Transient(None),
+            // Drive describes the most of the failures in the response body
+            client::Error::BadRequest(body) => {
+                let code = body["error"]["code"].as_u64().unwrap_or_default();
+                let reasons = body["error"]["errors"]
+                    .as_array()
+                    .map(|errors| {
+                        errors
+                            .iter()
+                            .filter_map(|error| error["reason"].as_str())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if is_transient(code, &reasons) {
+                    Failure::Transient(self.retry_after)
+                } else {
+                    Failure::Permanent
+                }
+            }
+            client::Error::Failure(response)
+                if is_transient(response.status().as_u16().into(), &[]) =>
+            {
+                Failure::Transient(self.retry_after)
+            }
+            _ => Failure::Permanent,
+        }
+    }
+}
+
+/// Delegate of a Drive API call, which keeps the `Retry-After` header of a failed response
+///
+/// The call is never retried by the client itself, as it would block the thread while waiting.
+#[derive(Default)]
+struct RetryAfter(Option<Duration>);
+
+impl RetryAfter {
+    /// Attaches the kept delay to the failure of the call
+    fn attach<T>(self, result: client::Result<T>) -> std::result::Result<T, DriveError> {
+        result.map_err(|error| DriveError {
+            error: Box::new(error),
+            retry_after: self.0,
+        })
+    }
+}
+
+impl client::Delegate for RetryAfter {
+    fn http_failure(
+        &mut self,
+        response: &hyper::Response<hyper::body::Body>,
+        _: Option<serde_json::Value>,
+    ) -> client::Retry {
+        self.0 = response
+            .headers()
+            .get(hyper::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        client::Retry::Abort
+    }
+}
+
+/// Kind of a change reported by the Drive Changes API
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeKind {
+    /// The file was created, edited, renamed or moved
+    Modified,
+    /// The file was moved to the trash
+    Trashed,
+    /// The file was permanently deleted, or access to it was lost
+    Removed,
+}
+
+/// A single entry of the change manifest, written to each snapshot
+#[derive(Serialize, Debug)]
+struct ChangeRecord {
+    file_id: String,
+    name: Option<String>,
+    kind: ChangeKind,
+    mime_type: Option<String>,
+    parents: Vec<String>,
+    time: Option<String>,
+    #[serde(skip)]
+    file: File,
+}
+
 impl DriveBackup {
     /// Lazily performs initial drive backup
-    async fn initial_backup(&self) {
+    ///
+    /// `start_page_token` must be acquired before the files are listed, so that
+    /// changes made during the initial backup are picked up by the next run.
+    /// Returns the report of the initial backup, if one has been done.
+    async fn initial_backup(&self, start_page_token: String) -> Result<Option<BackupReport>> {
         /* ---- PROCESSING SYSTEM STATE ---- */
         debug!("Checking if initial backup is required");
         {
-            let mut config = self.config.lock().await;
-            trace!(
-                "Google drive last update time is: {:?}",
-                config.google_drive.as_ref().unwrap().prev_update_time
-            );
-            if config
-                .google_drive
-                .as_ref()
-                .unwrap()
-                .prev_update_time
-                .is_none()
-            {
-                config.google_drive.as_mut().unwrap().prev_update_time =
-                    Some(Utc::now().to_rfc3339());
-                config.write();
-            } else {
+            let state = self.state.lock().await;
+            let page_token = state.source::<DriveState>(&self.name)?.page_token;
+            trace!("Google drive page token is: {:?}", page_token);
+            if page_token.is_some() {
                 debug!("No initial backup required");
-                return;
+                return Ok(None);
             }
         }
 
         /* ---- LOADING INITIAL VERSION OF THE FILES ---- */
-        info!("Performing initial backup of Google Drive");
+        info!("Performing initial backup of {}", self.report_source());
+        // A NEW DIRECTORY EVERY TIME, SO THAT A RE-INITIALIZATION NEVER WRITES OVER AN OLDER ONE,
+        // AND AN INTERRUPTED ONE IS DISCARDED AS ANY OTHER INCOMPLETE SNAPSHOT
         let base_directory = format!(
-            "{}/base",
-            self.config
-                .lock()
-                .await
-                .google_drive
-                .as_ref()
-                .unwrap()
-                .prefix
+            "{}/{}-base",
+            self.settings(|drive| drive.prefix.clone()).await?,
+            utc_to_string(Utc::now())
         );
         trace!("Base directory path: {}", base_directory);
+        let folders = self.folder_tree().await?;
         let files = self
-            .hub
-            .lock()
-            .await
-            .files()
-            .list()
-            .doit()
-            .await
-            .unwrap()
-            .1
-            .files
-            .unwrap();
+            .list_files(Some(&format!("mimeType != '{}'", FOLDER_MIME_TYPE)))
+            .await?;
         info!("The initial backup consists of {} file(s)", files.len());
-        let progress_bar = Arc::pin(Mutex::new(ProgressBar::new(files.len() as u64)));
-        progress_bar
-            .lock()
+        let files = files
+            .iter()
+            .map(|file| {
+                (
+                    format!(
+                        "{}{}",
+                        base_directory,
+                        folders.path(
+                            file.parents
+                                .as_ref()
+                                .and_then(|parents| parents.first())
+                                .map(String::as_str)
+                        )
+                    ),
+                    file,
+                )
+            })
+            .collect::<Vec<_>>();
+        let results = self
+            .download_drive_files(files, "Initial Backup Progress")
+            .await?;
+        let mut report = BackupReport::new(self.report_source());
+        for (file, result) in &results {
+            report.record(describe(file), result);
+        }
+        // AN EMPTY DRIVE HAS NO FILE TO CREATE THE DIRECTORY, BUT ITS REPORT STILL GOES THERE
+        std::fs::create_dir_all(&base_directory)
+            .map_err(|err| Error::filesystem(&base_directory, err))?;
+        report.snapshot = Some(base_directory.clone());
+        report.finish()?;
+
+        /* ---- PERSISTING SYSTEM STATE ---- */
+        {
+            let mut state = self.state.lock().await;
+            let mut drive_state = state.source::<DriveState>(&self.name)?;
+            drive_state.page_token = Some(start_page_token);
+            drive_state.failed_files = failed_ids(&results).map(|file_id| (file_id, 1)).collect();
+            drive_state.last_sync_time = Some(Utc::now().to_rfc3339());
+            drive_state.last_snapshot = report.snapshot.clone();
+            state.set_source(&self.name, &drive_state);
+            state.write()?;
+        }
+        info!("Done initial backup of {}", self.report_source());
+        Ok(Some(report))
+    }
+
+    /// Lists every file matching the `query`, on My Drive and on the shared drives alike,
+    /// following all the pages
+    async fn list_files(&self, query: Option<&str>) -> Result<Vec<File>> {
+        let page_size = self.page_size().await?;
+        let retry_policy = self.retry_policy().await;
+        let mut files = Vec::new();
+        let mut page_token = None::<String>;
+        let mut pages = 0;
+        loop {
+            let page_token_ref = &page_token;
+            let (_, file_list) = retry(&retry_policy, "list drive files", || async move {
+                let mut delegate = RetryAfter::default();
+                let mut call = self
+                    .hub
+                    .files()
+                    .list()
+                    .supports_all_drives(true)
+                    .include_items_from_all_drives(true)
+                    .corpora("allDrives")
+                    .page_size(page_size)
+                    .param("fields", "*");
+                if let Some(query) = query {
+                    call = call.q(query);
+                }
+                if let Some(page_token) = page_token_ref.as_ref() {
+                    call = call.page_token(page_token);
+                }
+                let result = call.delegate(&mut delegate).doit().await;
+                delegate.attach(result)
+            })
+            .await?;
+            pages += 1;
+            files.extend(file_list.files.unwrap_or_default());
+            trace!("Listed {} file(s) over {} page(s)", files.len(), pages);
+
+            match file_list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        info!(
+            "Enumerated {} drive file(s) over {} page(s)",
+            files.len(),
+            pages
+        );
+        Ok(files)
+    }
+
+    /// Collects every change since `page_token`
+    ///
+    /// Returns the changes, deduplicated by file id, along with the page token
+    /// the next incremental backup should start from.
+    async fn list_changes(&self, page_token: String) -> Result<(Vec<ChangeRecord>, String)> {
+        let page_size = self.page_size().await?;
+        let retry_policy = self.retry_policy().await;
+        let mut changes = HashMap::new();
+        let mut page_token = page_token;
+        let mut pages = 0;
+        loop {
+            trace!("Listing drive changes from page token {}", page_token);
+            let page_token_ref = &page_token;
+            let (_, change_list) = retry(&retry_policy, "list drive changes", || async move {
+                let mut delegate = RetryAfter::default();
+                let result = self
+                    .hub
+                    .changes()
+                    .list(page_token_ref)
+                    .supports_all_drives(true)
+                    .include_items_from_all_drives(true)
+                    .include_removed(true)
+                    .page_size(page_size)
+                    .param("fields", "*")
+                    .delegate(&mut delegate)
+                    .doit()
+                    .await;
+                delegate.attach(result)
+            })
+            .await?;
+            pages += 1;
+
+            change_list
+                .changes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|change| {
+                    let file_id = change.file_id?;
+                    let file = change.file.unwrap_or_default();
+                    let kind = if change.removed.unwrap_or(false) {
+                        ChangeKind::Removed
+                    } else if file.trashed.unwrap_or(false) {
+                        ChangeKind::Trashed
+                    } else {
+                        ChangeKind::Modified
+                    };
+                    Some(ChangeRecord {
+                        file_id,
+                        name: file.name.clone(),
+                        kind,
+                        mime_type: file.mime_type.clone(),
+                        parents: file.parents.clone().unwrap_or_default(),
+                        time: change.time,
+                        file,
+                    })
+                })
+                // Later changes of the same file supersede the earlier ones
+                .for_each(|record| {
+                    changes.insert(record.file_id.clone(), record);
+                });
+
+            match (
+                change_list.next_page_token,
+                change_list.new_start_page_token,
+            ) {
+                (Some(next_page_token), _) => page_token = next_page_token,
+                (None, Some(new_start_page_token)) => {
+                    info!(
+                        "Enumerated {} drive change(s) over {} page(s)",
+                        changes.len(),
+                        pages
+                    );
+                    return Ok((changes.into_values().collect(), new_start_page_token));
+                }
+                (None, None) => {
+                    return Err(Error::Api(
+                        "drive did not return a page token to continue from".into(),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Name of the source in the reports and logs
+    fn report_source(&self) -> String {
+        format!("{} `{}`", Self::NAME, self.name)
+    }
+
+    /// Reads the settings of this source, which a reload may have removed since the construction
+    async fn settings<T>(&self, read: impl FnOnce(&GoogleDriveConfig) -> T) -> Result<T> {
+        settings(&*self.config.lock().await, &self.name)
+            .map(read)
+            .ok_or_else(|| no_source(&self.name))
+    }
+
+    /// Page size used for the listings, as set in the config
+    async fn page_size(&self) -> Result<i32> {
+        self.settings(|drive| drive.page_size).await
+    }
+
+    /// Retry policy of the API calls, as set in the config
+    async fn retry_policy(&self) -> RetryConfig {
+        self.config.lock().await.retry.clone()
+    }
+
+    /// Upper bound of simultaneous downloads, as set in the config
+    async fn max_concurrent_downloads(&self) -> Result<usize> {
+        self.settings(|drive| drive.max_concurrent_downloads.max(1))
             .await
-            .set_message("Initial Backup Progress");
-        futures::future::join_all(
+    }
+
+    /// Downloads every file into its destination folder, as many at once as the config allows,
+    /// showing the progress under `message`
+    ///
+    /// The files sharing a name in a folder are told apart by their ids (see [`snapshot_names`]).
+    async fn download_drive_files<'a>(
+        &self,
+        files: Vec<(String, &'a File)>,
+        message: &'static str,
+    ) -> Result<Vec<(&'a File, Result<ItemOutcome>)>> {
+        let names = snapshot_names(&files);
+        let progress_bar = Arc::pin(Mutex::new(ProgressBar::new(files.len() as u64)));
+        progress_bar.lock().await.set_message(message);
+        let results = futures::stream::iter(
             files
                 .into_iter()
-                .map(|file| {
-                    (
-                        file.id.as_ref().unwrap().clone(),
-                        file.name.as_ref().unwrap().clone(),
-                    )
-                })
-                .map(|(id, name)| {
-                    let base_directory = base_directory.clone();
+                .zip(names)
+                .map(|((dest_folder, file), name)| {
                     let progress_bar = progress_bar.clone();
                     async move {
-                        self.download_drive_file(&base_directory, id.clone(), name.clone())
-                            .await;
+                        let result = self.download_drive_file(dest_folder, &name, file).await;
                         progress_bar.lock().await.inc(1);
+                        (file, result)
                     }
-                }),
+                })
+                // Collected eagerly, as the lazy iterator makes the future `!Send`
+                .collect::<Vec<_>>(),
         )
+        .buffer_unordered(self.max_concurrent_downloads().await?)
+        .collect::<Vec<_>>()
         .await;
         progress_bar.lock().await.finish();
-        info!("Done initial backup of Google Drive");
+        Ok(results)
     }
 
-    /// Downloads a single Drive file
+    /// Downloads a single Drive file as `file_name`, verifying it against the checksum
+    /// reported by Drive
     ///
-    /// If a file cannot be downloaded -- does nothing
+    /// Google-native files are exported instead.
     async fn download_drive_file(
         &self,
         dest_folder: impl AsRef<str>,
-        file_id: impl AsRef<str>,
-        file_name: impl AsRef<str>,
-    ) {
-        trace!("Downloading {} ({})", file_id.as_ref(), file_name.as_ref());
-        let dest_folder = Arc::pin(format!(
-            "{}/{}",
-            dest_folder.as_ref(),
-            self.discover_file_parents(&file_id).await
-        ));
-
+        file_name: &str,
+        file: &File,
+    ) -> Result<ItemOutcome> {
+        let file_id = file.id.as_deref().unwrap_or_default();
+        let mime_type = file.mime_type.as_deref().unwrap_or_default();
+        trace!("Downloading {} ({})", file_id, file_name);
         trace!(
             "Creating directory for file {}: `{}`",
-            file_id.as_ref(),
-            &*dest_folder
+            file_id,
+            dest_folder.as_ref()
         );
-        std::fs::create_dir_all(&*dest_folder).unwrap();
-        // Downloading file
-        if let Ok(x) = async {
-            self.hub
-                .lock()
-                .await
-                .files()
-                .get(file_id.as_ref())
-                .param("alt", "media")
-                .doit()
-                .await
+        std::fs::create_dir_all(dest_folder.as_ref())
+            .map_err(|err| Error::filesystem(dest_folder.as_ref(), err))?;
+
+        if mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX) {
+            return self
+                .export_drive_file(dest_folder, file_id, file_name, mime_type)
+                .await;
         }
-        .await
-        .map(|(body, _)| body)
-        .map(|body| {
-            let file_name = String::from(file_name.as_ref());
-            let dest_folder = dest_folder.clone();
-            async move {
-                std::fs::write(
-                    format!("{}/{}", dest_folder, file_name),
-                    body::to_bytes(body).await.unwrap(),
-                )
-                .unwrap();
+
+        let expected = Checksum {
+            size: file.size.as_deref().and_then(|size| size.parse().ok()),
+            md5: file.md5_checksum.clone(),
+        };
+        let attempts = self
+            .settings(|drive| drive.download_attempts.max(1))
+            .await?;
+        let retry_policy = self.retry_policy().await;
+        let mut attempt = 1;
+        loop {
+            let response = retry(&retry_policy, format!("download {}", file_id), || async {
+                let mut delegate = RetryAfter::default();
+                let result = self
+                    .hub
+                    .files()
+                    .get(file_id)
+                    .supports_all_drives(true)
+                    .param("alt", "media")
+                    .delegate(&mut delegate)
+                    .doit()
+                    .await;
+                delegate.attach(result)
+            })
+            .await;
+            let result = match response {
+                Ok((response, _)) => {
+                    write_response(
+                        format!("{}/{}", dest_folder.as_ref(), file_name),
+                        file_id,
+                        response,
+                        &expected,
+                    )
+                    .await
+                }
+                Err(err) => Err(err.into()),
+            };
+            match result {
+                Ok(bytes) => {
+                    trace!("Downloaded {} ({}), {} byte(s)", file_id, file_name, bytes);
+                    return Ok(ItemOutcome::Written(bytes));
+                }
+                Err(err) if attempt < attempts => {
+                    warn!(
+                        "Attempt {}/{} to back {} ({}) up has failed: {}",
+                        attempt, attempts, file_name, file_id, err
+                    );
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
             }
-        }) {
-            x.await;
-            trace!("Downloaded {} ({})", file_id.as_ref(), file_name.as_ref());
         }
-        // If failed to download the file -- trying to export it from Google Docs
-        else if let Some(ext) = futures::future::join_all(
-            [
-                // MAIN DOCUMENT FORMAT
-                ("application/vnd.oasis.opendocument.text", "odt"),
-                // MAIN TABLE FORMAT
-                ("application/x-vnd.oasis.opendocument.spreadsheet", "odf"),
-                // MAIN PRESENTATION FORMAT
-                ("application/vnd.oasis.opendocument.presentation", "odp"),
-                // FALLBACK FORMATS
-                ("application/rtf", "rtf"),
-                (
-                    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
-                    "googledoc",
-                ),
-                ("application/pdf", "pdf"),
-                ("application/epub+zip", "epub.zip"),
-                ("application/zip", "zip"),
-                ("text/html", "html"),
-                ("text/plain", "txt"),
-            ]
-            .into_iter()
-            .map(|(mime, ext)| {
-                let file_id = file_id.as_ref().to_string();
-                async move {
-                    self.hub
-                        .lock()
-                        .await
+    }
+
+    /// Exports a single Google-native file to every copy configured for its type
+    ///
+    /// Files of types that cannot be exported are skipped. Fails, if any of the copies
+    /// could not be exported.
+    async fn export_drive_file(
+        &self,
+        dest_folder: impl AsRef<str>,
+        file_id: impl AsRef<str>,
+        file_name: impl AsRef<str>,
+        mime_type: impl AsRef<str>,
+    ) -> Result<ItemOutcome> {
+        let copies = match GoogleDocType::from_mime_type(mime_type.as_ref()) {
+            Some(doc_type) => {
+                self.settings(|drive| {
+                    drive
+                        .export_formats
+                        .get(&doc_type)
+                        .cloned()
+                        .unwrap_or_default()
+                })
+                .await?
+            }
+            None => {
+                debug!(
+                    "Skipping {} ({}): files of type {} cannot be exported",
+                    file_name.as_ref(),
+                    file_id.as_ref(),
+                    mime_type.as_ref()
+                );
+                return Ok(ItemOutcome::Skipped);
+            }
+        };
+
+        let mut result = Ok(0);
+        for formats in copies {
+            if let Some(bytes) = self
+                .export_drive_file_copy(&dest_folder, &file_id, &file_name, &formats)
+                .await
+            {
+                result = result.map(|total| total + bytes);
+            } else {
+                let extensions = formats
+                    .iter()
+                    .map(|format| format.extension.as_str())
+                    .collect::<Vec<_>>();
+                warn!(
+                    "Failed to export {} ({}) as any of {:?}",
+                    file_name.as_ref(),
+                    file_id.as_ref(),
+                    extensions
+                );
+                result = Err(Error::Api(format!(
+                    "failed to export as any of {:?}",
+                    extensions
+                )));
+            }
+        }
+        result.map(ItemOutcome::Written)
+    }
+
+    /// Exports a single copy of a Google-native file, trying the `formats` in order
+    ///
+    /// Returns the number of bytes written, if any of the formats succeeded
+    async fn export_drive_file_copy(
+        &self,
+        dest_folder: impl AsRef<str>,
+        file_id: impl AsRef<str>,
+        file_name: impl AsRef<str>,
+        formats: &[ExportFormat],
+    ) -> Option<u64> {
+        let retry_policy = self.retry_policy().await;
+        for ExportFormat {
+            mime_type,
+            extension,
+        } in formats
+        {
+            let response = retry(
+                &retry_policy,
+                format!("export {} as {}", file_id.as_ref(), extension),
+                || async {
+                    let mut delegate = RetryAfter::default();
+                    let result = self
+                        .hub
                         .files()
-                        .export(file_id.as_ref(), mime)
+                        .export(file_id.as_ref(), mime_type)
+                        .delegate(&mut delegate)
                         .doit()
-                        .await
-                        .map(|res| (res, ext))
+                        .await;
+                    delegate.attach(result)
+                },
+            )
+            .await;
+            match response {
+                Ok(response) => {
+                    match write_response(
+                        format!(
+                            "{}/{}.{}",
+                            dest_folder.as_ref(),
+                            file_name.as_ref(),
+                            extension
+                        ),
+                        file_id.as_ref(),
+                        response,
+                        &Checksum::default(),
+                    )
+                    .await
+                    {
+                        Ok(bytes) => {
+                            trace!(
+                                "Exported {} ({}) as {}, {} byte(s)",
+                                file_id.as_ref(),
+                                file_name.as_ref(),
+                                extension,
+                                bytes
+                            );
+                            return Some(bytes);
+                        }
+                        Err(err) => debug!(
+                            "Failed to write {} ({}) as {}: {}",
+                            file_name.as_ref(),
+                            file_id.as_ref(),
+                            extension,
+                            err
+                        ),
+                    }
                 }
-            }),
-        )
-        .await
-        .into_iter()
-        .find(Result::is_ok)
-        .map(Result::unwrap)
-        .map(|(res, ext)| {
-            let file_name = file_name.as_ref().to_string();
-            async move {
-                std::fs::write(
-                    format!("{}/{}.{}", dest_folder, file_name, ext),
-                    body::to_bytes(res).await.unwrap(),
-                )
-                .unwrap();
-                ext
+                Err(err) => debug!(
+                    "Failed to export {} ({}) as {}: {}",
+                    file_name.as_ref(),
+                    file_id.as_ref(),
+                    mime_type,
+                    err
+                ),
             }
-        }) {
-            trace!(
-                "Exported {} ({}) as {}",
-                file_id.as_ref(),
-                file_name.as_ref(),
-                ext.await
+        }
+        None
+    }
+
+    /// Lists the ids and the names of the shared drives, following all the pages
+    async fn list_drives(&self) -> Result<Vec<(String, String)>> {
+        let retry_policy = self.retry_policy().await;
+        let mut drives = Vec::new();
+        let mut page_token = None::<String>;
+        loop {
+            let page_token_ref = &page_token;
+            let (_, drive_list) = retry(&retry_policy, "list shared drives", || async move {
+                let mut delegate = RetryAfter::default();
+                let mut call = self.hub.drives().list().page_size(100);
+                if let Some(page_token) = page_token_ref.as_ref() {
+                    call = call.page_token(page_token);
+                }
+                let result = call.delegate(&mut delegate).doit().await;
+                delegate.attach(result)
+            })
+            .await?;
+            drives.extend(
+                drive_list
+                    .drives
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|drive| drive.id.zip(drive.name)),
             );
-        } else {
-            warn!(
-                "Failed to download {} ({})",
-                file_name.as_ref(),
-                file_id.as_ref(),
-            )
+            match drive_list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
         }
+        Ok(drives)
     }
 
-    /// Given file file id, returns its path on the drive _excluding the filename_
-    async fn discover_file_parents(&self, file_id: impl AsRef<str>) -> String {
-        // Iteratively getting the file's full path
-        {
-            let mut file_id = Some(String::from(file_id.as_ref()));
-            let mut path = LinkedList::new();
-            while file_id.is_some() {
-                let hub = self.hub.lock().await;
-                let (_, file) = hub
-                    .files()
-                    .get(file_id.as_ref().unwrap())
-                    .param("fields", "*")
-                    .doit()
-                    .await
-                    .unwrap();
-                file_id = file.parents.and_then(|parents| parents.into_iter().next());
-                path.push_front(
-                    file.name
-                        .unwrap_or_else(|| file_id.as_ref().unwrap().clone()),
-                );
+    /// Builds the folder tree cache from a single listing of the drive folders
+    async fn folder_tree(&self) -> Result<FolderTree> {
+        trace!("Building drive folder tree");
+        let (_, root) = retry(&self.retry_policy().await, "get drive root", || async {
+            let mut delegate = RetryAfter::default();
+            let result = self
+                .hub
+                .files()
+                .get("root")
+                .param("fields", "*")
+                .delegate(&mut delegate)
+                .doit()
+                .await;
+            delegate.attach(result)
+        })
+        .await?;
+        let mut folders = self
+            .list_files(Some(&format!("mimeType = '{}'", FOLDER_MIME_TYPE)))
+            .await?
+            .into_iter()
+            .chain(std::iter::once(root))
+            .filter_map(|folder| {
+                let parent = folder
+                    .parents
+                    .and_then(|parents| parents.into_iter().next());
+                folder
+                    .id
+                    .zip(folder.name)
+                    .map(|(id, name)| (id, (name, parent)))
+            })
+            .collect::<HashMap<_, _>>();
+        // THE ROOTS OF THE SHARED DRIVES ARE NOT FOLDERS, BUT THEIR TOP-LEVEL FILES NAME THEM AS PARENTS
+        let drives = self.list_drives().await?;
+        if !drives.is_empty() {
+            folders.insert(SHARED_DRIVES_ID.into(), ("Shared drives".into(), None));
+        }
+        for (id, name) in &drives {
+            let shared_name = drives.iter().filter(|(_, other)| other == name).count() > 1;
+            let name = if shared_name {
+                format!("{}-{}", name, id)
+            } else {
+                name.clone()
+            };
+            folders.insert(id.clone(), (name, Some(SHARED_DRIVES_ID.into())));
+        }
+        debug!(
+            "Drive folder tree consists of {} folder(s) over {} shared drive(s)",
+            folders.len(),
+            drives.len()
+        );
+        Ok(FolderTree { folders })
+    }
+}
+
+/// Per-run cache of the drive folders, used to resolve file paths without API calls
+struct FolderTree {
+    /// Maps folder id to its name and parent folder id
+    folders: HashMap<String, (String, Option<String>)>,
+}
+
+impl FolderTree {
+    /// Given the id of the file's parent folder, returns its path on the drive _excluding the filename_
+    ///
+    /// Folders missing from the tree (e.g. not shared with the user) terminate the path.
+    fn path(&self, parent_id: Option<&str>) -> String {
+        // Iteratively getting the folder's full path
+        let mut path = LinkedList::new();
+        let mut folder_id = parent_id;
+        while let Some((name, parent)) = folder_id.and_then(|id| self.folders.get(id)) {
+            // Guarding against cycles in malformed trees
+            if path.len() > self.folders.len() {
+                warn!("Drive folder tree contains a cycle at {:?}", folder_id);
+                break;
             }
-            path.pop_back(); // Removing filename, as it isn't a folder
-            path
+            path.push_front(name.as_str());
+            folder_id = parent.as_deref();
         }
         // Generating the path string
-        .into_iter()
-        .fold(String::new(), |mut partial_path, element| {
-            partial_path += "/";
-            partial_path += &element;
-            partial_path
-        })
+        path.into_iter()
+            .fold(String::new(), |mut partial_path, element| {
+                partial_path += "/";
+                partial_path += element;
+                partial_path
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(id: &str, name: &str) -> File {
+        File {
+            id: Some(id.into()),
+            name: Some(name.into()),
+            ..File::default()
+        }
+    }
+
+    #[test]
+    fn files_sharing_a_name_in_a_folder_are_told_apart_by_their_ids() {
+        let (first, second) = (
+            file("a1", "Untitled document"),
+            file("b2", "Untitled document"),
+        );
+        let (photo, other_photo) = (file("c3", "photo.jpg"), file("d4", "photo.jpg"));
+        let elsewhere = file("e5", "photo.jpg");
+        let files = [
+            ("base/".to_string(), &first),
+            ("base/".to_string(), &second),
+            ("base/".to_string(), &photo),
+            ("base/".to_string(), &other_photo),
+            ("base/album/".to_string(), &elsewhere),
+        ];
+        assert_eq!(
+            snapshot_names(&files),
+            [
+                "Untitled document-a1",
+                "Untitled document-b2",
+                "photo-c3.jpg",
+                "photo-d4.jpg",
+                "photo.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_drives_are_kept_apart_from_my_drive() {
+        let folder =
+            |name: &str, parent: Option<&str>| (name.to_string(), parent.map(String::from));
+        let tree = FolderTree {
+            folders: HashMap::from([
+                ("root".to_string(), folder("My Drive", None)),
+                ("f1".to_string(), folder("Docs", Some("root"))),
+                (SHARED_DRIVES_ID.to_string(), folder("Shared drives", None)),
+                ("d1".to_string(), folder("Team", Some(SHARED_DRIVES_ID))),
+                ("f2".to_string(), folder("Docs", Some("d1"))),
+            ]),
+        };
+        assert_eq!(tree.path(Some("f1")), "/My Drive/Docs");
+        assert_eq!(tree.path(Some("d1")), "/Shared drives/Team");
+        assert_eq!(tree.path(Some("f2")), "/Shared drives/Team/Docs");
+    }
+
+    #[test]
+    fn rate_limited_call_waits_as_long_as_drive_asks() {
+        use client::Delegate;
+
+        let response = hyper::Response::builder()
+            .status(429)
+            .header(hyper::header::RETRY_AFTER, "30")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let body = serde_json::json!({"error": {"code": 429, "errors": [{"reason": "rateLimitExceeded"}]}});
+        let mut delegate = RetryAfter::default();
+        delegate.http_failure(&response, Some(body.clone()));
+        let err = delegate
+            .attach::<()>(Err(client::Error::BadRequest(body)))
+            .unwrap_err();
+        assert_eq!(
+            err.classify(),
+            Failure::Transient(Some(Duration::from_secs(30)))
+        );
     }
 }
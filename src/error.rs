@@ -0,0 +1,84 @@
+use std::fmt::Display;
+
+use google_drive3::client;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Failure of a backup source
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to authenticate against the source
+    Auth(String),
+    /// The request could not be sent, or the response could not be received
+    Network(String),
+    /// The source has rejected the request, or responded with something unexpected
+    Api(String),
+    /// Failed to read or write a local file
+    Filesystem {
+        path: String,
+        source: std::io::Error,
+    },
+    /// A downloaded file does not match the checksum reported by the source
+    Integrity(String),
+    /// The configuration is missing or invalid
+    Config(String),
+}
+
+impl Error {
+    /// Wraps an IO error, which occurred while accessing the `path`
+    pub fn filesystem(path: impl AsRef<std::path::Path>, source: std::io::Error) -> Self {
+        Self::Filesystem {
+            path: path.as_ref().display().to_string(),
+            source,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Auth(message) => write!(f, "authentication error: {}", message),
+            Error::Network(message) => write!(f, "network error: {}", message),
+            Error::Api(message) => write!(f, "API error: {}", message),
+            Error::Filesystem { path, source } => {
+                write!(f, "filesystem error at `{}`: {}", path, source)
+            }
+            Error::Integrity(message) => write!(f, "integrity error: {}", message),
+            Error::Config(message) => write!(f, "configuration error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Filesystem { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<client::Error> for Error {
+    fn from(err: client::Error) -> Self {
+        match err {
+            client::Error::HttpError(_) | client::Error::Io(_) => Error::Network(err.to_string()),
+            client::Error::MissingToken(_) | client::Error::MissingAPIKey => {
+                Error::Auth(err.to_string())
+            }
+            client::Error::BadRequest(ref body) if body["error"]["code"].as_u64() == Some(401) => {
+                Error::Auth(err.to_string())
+            }
+            client::Error::Failure(ref response) if response.status().as_u16() == 401 => {
+                Error::Auth(err.to_string())
+            }
+            _ => Error::Api(err.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        // THE URL MAY CARRY THE CREDENTIALS
+        Error::Network(err.without_url().to_string())
+    }
+}
@@ -1,14 +1,81 @@
+use clap::Parser;
 use flexi_logger::detailed_format;
 use log::*;
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::util::{await_next_call, Backup};
+use crate::{
+    cli::{Cli, Command},
+    config::{Config, LogLevel},
+    error::Error,
+    report::BackupReport,
+    state::State,
+    util::Lock,
+};
 
 #[tokio::main]
 async fn main() {
-    let config = Arc::pin(tokio::sync::Mutex::new(config::init()));
+    let cli = Cli::parse();
+    let state_path = cli.state_path();
+    match cli.command.unwrap_or(Command::Run {
+        watch_config: false,
+    }) {
+        Command::Run { watch_config } => {
+            let (config, state) = setup(&cli.config, &state_path);
+            if !run(cli.config, config, state, watch_config).await {
+                std::process::exit(1);
+            }
+        }
+        Command::Once { source } => {
+            let (config, state) = setup(&cli.config, &state_path);
+            if !once(config, state, source).await {
+                std::process::exit(1);
+            }
+        }
+        Command::Init { sources, force } => init_config(&cli.config, &sources, force),
+        Command::CheckConfig => check_config(&cli.config),
+        Command::ListSnapshots => list_snapshots(&read_config(&cli.config)),
+        Command::Status => status(&cli.config, &state_path),
+    }
+}
+
+/// Loads the config and the state, and starts the logger
+fn setup(config_path: &Path, state_path: &Path) -> (Lock<Config>, Lock<State>) {
+    let config = read_config(config_path);
 
     // LOGGER SETUP
+    start_logger(config.cmd_log_level);
+
+    // CONFIG VALIDATION
+    let problems = config.validate();
+    if !problems.is_empty() {
+        error!(
+            "The configuration at `{}` is invalid:",
+            config_path.display()
+        );
+        for problem in problems {
+            error!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+
+    // STATE SETUP
+    let state = match State::init(state_path) {
+        Ok(state) => state,
+        Err(err) => {
+            error!("Failed to read the synchronization state: {}", err);
+            std::process::exit(1);
+        }
+    };
+    (
+        Arc::pin(tokio::sync::Mutex::new(config)),
+        Arc::pin(tokio::sync::Mutex::new(state)),
+    )
+}
+
+fn start_logger(cmd_log_level: LogLevel) {
     flexi_logger::Logger::try_with_str("info, vectorcircles_auto_backup=trace")
         .unwrap()
         .format_for_files(detailed_format)
@@ -17,71 +84,247 @@ async fn main() {
                 .directory("log")
                 .basename(sys_info::hostname().unwrap_or_else(|_| String::from("unknown"))),
         )
-        .duplicate_to_stdout(config.lock().await.cmd_log_level.into())
+        .duplicate_to_stdout(cmd_log_level.into())
         .start()
         .unwrap();
+}
 
-    /* ---- ROUTINE DEFINITIONS ---- */
-    // DRIVE BACKUP ROUTINE
-    let drive_routine = {
-        let config = config.clone();
-        async move {
-            if config.lock().await.google_drive.is_some() {
-                let drive = drive_backup::DriveBackup::new(config.clone()).await;
-                let cron = config
-                    .lock()
-                    .await
-                    .google_drive
-                    .as_ref()
-                    .unwrap()
-                    .backup_cron
-                    .clone();
-                loop {
-                    debug!("Awaiting for the next backup call.");
-                    await_next_call(&cron).await;
-                    debug!("Backup call received");
-                    {
-                        trace!("Calling `drive.backup_changes`");
-                        drive.backup_changes().await;
-                        trace!("Finished `drive.backup_changes`");
-                    }
-                }
+/// Reads the config, exiting with an error message if it cannot be loaded
+///
+/// The logger is not running yet, so the message is printed directly.
+fn read_config(path: &Path) -> Config {
+    Config::read(path).unwrap_or_else(|err| {
+        match err {
+            Error::Filesystem { ref source, .. }
+                if source.kind() == std::io::ErrorKind::NotFound =>
+            {
+                eprintln!(
+                    "No configuration file found at `{}`. Run `{} init` to create one.",
+                    path.display(),
+                    env!("CARGO_PKG_NAME")
+                );
             }
+            _ => eprintln!("Failed to load the configuration: {}", err),
         }
+        std::process::exit(1);
+    })
+}
+
+/// Runs the backups on their schedules, until SIGTERM or SIGINT is received
+///
+/// Returns, whether the backups running at that moment have finished within the shutdown timeout.
+async fn run(
+    config_path: PathBuf,
+    config: Lock<Config>,
+    state: Lock<State>,
+    watch_config: bool,
+) -> bool {
+    let (reload_sender, reloads) = tokio::sync::watch::channel(());
+    let (stop_sender, stop) = tokio::sync::watch::channel(false);
+    let control = async {
+        tokio::select! {
+            _ = reload::watch_config(config_path, config.clone(), reload_sender, watch_config) => {}
+            signal = shutdown::requested() => {
+                info!("{} received, finishing the running backups.", signal)
+            }
+        }
+        let _ = stop_sender.send(true);
     };
-    // TRELLO BACKUP ROUTINE
-    let trello_routine = {
-        let config = config.clone();
-        async move {
-            if config.lock().await.trello.is_some() {
-                let trello = trello_backup::TrelloBackup::new(config.clone()).await;
-                let cron = config
-                    .lock()
-                    .await
-                    .trello
-                    .as_ref()
-                    .unwrap()
-                    .backup_cron
-                    .clone();
-                loop {
-                    debug!("Awaiting for the next trello backup call.");
-                    await_next_call(&cron).await;
-                    debug!("Backup call received");
-                    {
-                        trace!("Calling `trello.backup_changes`");
-                        trello.backup_changes().await;
-                        trace!("Finished `trello.backup_changes`");
-                    }
+    let (clean, ()) = futures::join!(
+        registry::schedule_all(config.clone(), state, reloads, stop),
+        control
+    );
+    if clean {
+        info!("Stopped, every backup has finished.");
+    } else {
+        error!(
+            "Stopped, the unfinished backups have been cancelled and their snapshots discarded."
+        );
+    }
+    clean
+}
+
+/// Backs every configured source (or only the source named `source`) up once
+///
+/// On SIGTERM or SIGINT, the running backup is given the shutdown timeout to finish,
+/// and the rest of the sources are skipped.
+/// Returns, whether every source has been backed up without failures.
+async fn once(config: Lock<Config>, state: Lock<State>, source: Option<String>) -> bool {
+    let sources = {
+        let config = config.lock().await;
+        config
+            .sources
+            .iter()
+            .filter(|candidate| source.is_none() || source.as_ref() == Some(&candidate.name))
+            .filter_map(|candidate| {
+                registry::source_type_of(&config, &candidate.name)
+                    .map(|source_type| (candidate.name.clone(), source_type))
+            })
+            .collect::<Vec<_>>()
+    };
+    if sources.is_empty() {
+        match source {
+            Some(source) => error!("There is no source named `{}`.", source),
+            None => error!("No source to back up is configured."),
+        }
+        return false;
+    }
+
+    let (stop_sender, stop) = tokio::sync::watch::channel(false);
+    let control = tokio::spawn(async move {
+        let signal = shutdown::requested().await;
+        info!("{} received, finishing the running backup.", signal);
+        let _ = stop_sender.send(true);
+    });
+    let mut results = Vec::new();
+    for (name, source_type) in sources {
+        let report = source_type
+            .once(name.clone(), config.clone(), state.clone(), stop.clone())
+            .await;
+        results.push((name, report));
+    }
+    control.abort();
+
+    // SUMMARY
+    let mut success = true;
+    for (name, reports) in results {
+        match reports {
+            Some(reports) => {
+                for report in reports {
+                    success &= report.is_success();
+                    println!("{}", report);
                 }
             }
+            None => {
+                success = false;
+                println!("{}: failed", name);
+            }
         }
-    };
+    }
+    success
+}
+
+/// Prints every problem of the config at `path`, exiting with an error if there are any
+fn check_config(path: &Path) {
+    let problems = read_config(path).validate();
+    if problems.is_empty() {
+        println!("Configuration at `{}` is valid.", path.display());
+        return;
+    }
+    eprintln!("The configuration at `{}` is invalid:", path.display());
+    for problem in problems {
+        eprintln!("  {}", problem);
+    }
+    std::process::exit(1);
+}
 
-    /* ---- LAUNCHING ROUTINES ---- */
-    futures::join!(drive_routine, trello_routine);
+/// Writes the config template to `path`, with the `sources` enabled
+fn init_config(path: &Path, sources: &[String], force: bool) {
+    if path.exists() && !force {
+        eprintln!(
+            "`{}` already exists, pass `--force` to overwrite it.",
+            path.display()
+        );
+        std::process::exit(1);
+    }
+    let template = Config::template(
+        &registry::source_types()
+            .into_iter()
+            .map(|source_type| {
+                (
+                    sources.iter().any(|source| source == source_type.kind),
+                    (source_type.template)(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    if let Err(err) = std::fs::write(path, template) {
+        eprintln!(
+            "Failed to write the configuration file `{}`: {}",
+            path.display(),
+            err
+        );
+        std::process::exit(1);
+    }
+    println!(
+        "Configuration written to `{}`. Please, fill it up and check it with `check-config`.",
+        path.display()
+    );
+}
+
+/// Prints the snapshots under the prefix of every configured source
+fn list_snapshots(config: &Config) {
+    for source in &config.sources {
+        let prefix = match registry::source_type_of(config, &source.name)
+            .and_then(|source_type| (source_type.section)(config, &source.name))
+        {
+            Some(section) => section.prefix,
+            None => continue,
+        };
+        println!("{} (`{}`):", source.name, prefix);
+        let mut snapshots = match std::fs::read_dir(&prefix) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>(),
+            Err(err) => {
+                println!("  cannot be read: {}", err);
+                continue;
+            }
+        };
+        snapshots.sort();
+        if snapshots.is_empty() {
+            println!("  no snapshots");
+        }
+        for snapshot in snapshots {
+            // SNAPSHOTS, WRITTEN BEFORE THE REPORTS WERE INTRODUCED, HAVE NONE
+            let report = std::fs::read_to_string(snapshot.join("report.json"))
+                .ok()
+                .and_then(|source| serde_json::from_str::<BackupReport>(&source).ok());
+            match report {
+                Some(report) => println!("  {}", report),
+                None => println!("  {}", snapshot.display()),
+            }
+        }
+    }
+}
+
+/// Prints the synchronization state of every configured source
+fn status(config_path: &Path, state_path: &Path) {
+    let config = read_config(config_path);
+    let state = State::init(state_path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    for source in &config.sources {
+        let source_type = match registry::source_type_of(&config, &source.name) {
+            Some(source_type) => source_type,
+            None => continue,
+        };
+        println!("{} ({}):", source.name, source_type.name);
+        println!(
+            "  last run: {}",
+            state
+                .last_runs
+                .get(&source.name)
+                .map(String::as_str)
+                .unwrap_or("never")
+        );
+        for line in (source_type.status)(&state, &source.name) {
+            println!("  {}", line);
+        }
+    }
 }
 
+pub mod cli;
 pub mod config;
 pub mod drive_backup;
+pub mod error;
+pub mod registry;
+pub mod reload;
+pub mod report;
+pub mod shutdown;
+pub mod state;
 pub mod trello_backup;
 pub mod util;
@@ -0,0 +1,726 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use log::*;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::{
+    config::{CatchUpPolicy, Config, OverlapPolicy, Timezone, Timing},
+    drive_backup::DriveBackup,
+    error,
+    reload::Reloads,
+    report::BackupReport,
+    shutdown::{stopped, Stop},
+    state::State,
+    trello_backup::TrelloBackup,
+    util::{await_time, defer_call, missed_call, next_call, Backup, Lock, SourceSection},
+};
+
+/// Every source type known to the program
+///
+/// A new integration only has to implement [`Backup`] and be listed here.
+pub fn source_types() -> Vec<SourceType> {
+    vec![
+        SourceType::of::<DriveBackup>(),
+        SourceType::of::<TrelloBackup>(),
+    ]
+}
+
+/// Type of the configured source `name`
+pub fn source_type_of(config: &Config, name: &str) -> Option<SourceType> {
+    source_types()
+        .into_iter()
+        .find(|source_type| (source_type.section)(config, name).is_some())
+}
+
+type Routine = BoxFuture<'static, bool>;
+type OneShot = BoxFuture<'static, Option<Vec<BackupReport>>>;
+type Validation = fn(&Config, &str, &mut dyn FnMut(&str, String));
+
+/// Type-erased [`Backup`] implementation
+pub struct SourceType {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub secret_fields: &'static [&'static str],
+    pub section: fn(&Config, &str) -> Option<SourceSection>,
+    pub validate: Validation,
+    pub template: fn() -> String,
+    pub status: fn(&State, &str) -> Vec<String>,
+    schedule: fn(String, Lock<Config>, Lock<State>, Reloads, Stop) -> Routine,
+    once: fn(String, Lock<Config>, Lock<State>, Stop) -> OneShot,
+}
+
+impl SourceType {
+    fn of<B: Backup>() -> Self {
+        Self {
+            name: B::NAME,
+            kind: B::KIND,
+            secret_fields: B::SECRET_FIELDS,
+            section: B::section,
+            validate: B::validate,
+            template: B::template,
+            status: B::status,
+            schedule: |name, config, state, reloads, stop| {
+                Box::pin(schedule::<B>(name, config, state, reloads, stop))
+            },
+            once: |name, config, state, stop| Box::pin(backup_once::<B>(name, config, state, stop)),
+        }
+    }
+
+    /// Runs the backups of the source `name` on its schedule, until it is removed from the config
+    /// or the program is stopped
+    pub fn schedule(
+        &self,
+        name: String,
+        config: Lock<Config>,
+        state: Lock<State>,
+        reloads: Reloads,
+        stop: Stop,
+    ) -> Routine {
+        (self.schedule)(name, config, state, reloads, stop)
+    }
+
+    /// Backs the source `name` up once, returning the reports of the initial backup (if any)
+    /// and of the changes, or `None` if the backup has failed as a whole
+    ///
+    /// Once `stop` is set, the backup is given the shutdown timeout to finish.
+    pub fn once(
+        &self,
+        name: String,
+        config: Lock<Config>,
+        state: Lock<State>,
+        stop: Stop,
+    ) -> OneShot {
+        (self.once)(name, config, state, stop)
+    }
+}
+
+/// Runs every configured source on its schedule, until the process is stopped
+///
+/// The sources, added by a reload of the config, are started as soon as it is reloaded.
+/// Once `stop` is set, no new backup is started, and the running ones are given
+/// the shutdown timeout to finish. Returns, whether all of them have finished in time.
+pub async fn schedule_all(
+    config: Lock<Config>,
+    state: Lock<State>,
+    mut reloads: Reloads,
+    mut stop: Stop,
+) -> bool {
+    let mut routines = HashMap::<(String, &'static str), JoinHandle<bool>>::new();
+    loop {
+        {
+            let current_config = config.lock().await;
+            // THE ROUTINES OF THE REMOVED SOURCES STOP BY THEMSELVES
+            routines.retain(|_, routine| !routine.is_finished());
+            for source in &current_config.sources {
+                let source_type = match source_type_of(&current_config, &source.name) {
+                    Some(source_type) => source_type,
+                    None => continue,
+                };
+                routines
+                    .entry((source.name.clone(), source_type.kind))
+                    .or_insert_with(|| {
+                        tokio::spawn(source_type.schedule(
+                            source.name.clone(),
+                            config.clone(),
+                            state.clone(),
+                            reloads.clone(),
+                            stop.clone(),
+                        ))
+                    });
+            }
+        }
+        tokio::select! {
+            Ok(()) = reloads.changed() => {}
+            _ = stopped(&mut stop) => break,
+        }
+    }
+
+    // SHUTDOWN
+    let mut clean = true;
+    for (_, routine) in routines {
+        clean &= routine.await.unwrap_or(false);
+    }
+    clean
+}
+
+/// Backup routine of the source `name` of type `B`
+///
+/// The schedule is re-read from `config` before every call,
+/// and a reload of the config interrupts the wait for the next call. The backup object is kept
+/// across the reloads, unless the credentials of the source have changed.
+/// Once the source is initialized, the calls missed since the last run are caught up
+/// according to the config. A failed initialization is retried on the next call, which then
+/// goes on to back the source up.
+/// The backups run in the background, so the calls, due while one is running,
+/// are handled according to the overlap policy of the source instead of being silently lost.
+///
+/// Returns, once the source is removed or `stop` is set, whether the backup
+/// running at that moment (if any) has finished within the shutdown timeout.
+async fn schedule<B: Backup>(
+    name: String,
+    config: Lock<Config>,
+    state: Lock<State>,
+    mut reloads: Reloads,
+    mut stop: Stop,
+) -> bool {
+    let mut backup = None;
+    let mut built_with = Vec::new();
+    let mut running: Option<Run<error::Result<BackupReport>>> = None;
+    let mut last_start: Option<Instant> = None;
+    let mut starting = true;
+    let mut queued = false;
+    let mut next: Option<NextCall> = None;
+    // CALL, WHICH HAS FOUND THE SOURCE UNINITIALIZED, AND IS MADE ONCE IT IS INITIALIZED
+    let mut call_on_init = false;
+    loop {
+        if *stop.borrow() {
+            break;
+        }
+        let schedule = {
+            let config = config.lock().await;
+            B::section(&config, &name).map(|section| (section, config.timing(&name)))
+        };
+        let (section, timing) = match schedule {
+            Some(schedule) => schedule,
+            None => {
+                info!("{} source `{}` has been removed", B::NAME, name);
+                if let Some(run) = running.take() {
+                    let result = run.handle.await;
+                    finish_run::<B>(&name, &state, run.started_at, result).await;
+                }
+                return true;
+            }
+        };
+        if backup.is_some() && built_with != section.credentials {
+            info!(
+                "The credentials of {} source `{}` have changed, reinitializing it",
+                B::NAME,
+                name
+            );
+            backup = None;
+        }
+        if backup.is_none() {
+            built_with = section.credentials.clone();
+            // THE INITIALIZATION MAY INCLUDE THE INITIAL BACKUP, SO IT IS STOPPED LIKE ONE
+            let mut init = Run::start(
+                section.prefix.clone(),
+                B::new(name.clone(), config.clone(), state.clone()),
+            );
+            let init_started_at = init.started_at;
+            let (result, running_finished) = tokio::select! {
+                result = &mut init.handle => (Some(result), None),
+                _ = stopped(&mut stop) => {
+                    let timeout = config.lock().await.shutdown_timeout();
+                    // THE BACKUP, STILL RUNNING WITH THE OLD CREDENTIALS, IS GIVEN THE SAME TIME
+                    let (result, running_finished) = tokio::join!(
+                        init.finish_within(timeout, &name),
+                        wind_down::<B>(&name, &state, running.take(), timeout),
+                    );
+                    (result, Some(running_finished))
+                }
+            };
+            let init_finished = result.is_some();
+            match result {
+                Some(Ok(Ok((new_backup, initial_report)))) => {
+                    if let Some(report) = initial_report {
+                        report.log();
+                        record_run(&state, &name, init_started_at).await;
+                    }
+                    backup = Some(Arc::new(new_backup));
+                }
+                Some(Ok(Err(err))) => error!(
+                    "Failed to initialize {} backup `{}`, retrying on the next call: {}",
+                    B::NAME,
+                    name,
+                    err
+                ),
+                Some(Err(err)) => error!(
+                    "Initialization of {} backup `{}` has crashed: {}",
+                    B::NAME,
+                    name,
+                    err
+                ),
+                None => {}
+            }
+            if let Some(running_finished) = running_finished {
+                return init_finished && running_finished;
+            }
+        }
+        // THE CATCH-UP WAITS FOR THE SOURCE TO BE INITIALIZED, E.G. FOR THE NETWORK TO BE UP AT BOOT
+        if starting && backup.is_some() {
+            starting = false;
+            let call_now = catch_up(
+                &name,
+                &section.backup_cron,
+                timing.timezone,
+                &config,
+                &state,
+            )
+            .await;
+            if call_now {
+                info!("Catching up on the missed `{}` backup call.", name);
+                next = Some(NextCall::at_once(Utc::now(), &timing));
+            }
+        }
+        if !(std::mem::take(&mut call_on_init) && backup.is_some()) {
+            let due = NextCall::due(&mut next, &section.backup_cron, &timing, Utc::now());
+            match due {
+                Some(due) => debug!("Awaiting for the next `{}` backup call at {}.", name, due),
+                None => error!(
+                    "The schedule `{}` of `{}` has no more calls, awaiting a reload of the config",
+                    section.backup_cron, name
+                ),
+            }
+            let call = async {
+                match due {
+                    Some(due) => await_time(due).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = call => {}
+                result = async { (&mut running.as_mut().unwrap().handle).await }, if running.is_some() => {
+                    let run = running.take().unwrap();
+                    finish_run::<B>(&name, &state, run.started_at, result).await;
+                    if std::mem::take(&mut queued) {
+                        next = Some(NextCall::at_once(Utc::now(), &timing));
+                    }
+                    continue;
+                }
+                Ok(()) = reloads.changed() => continue,
+                _ = stopped(&mut stop) => break,
+            }
+        }
+        debug!("`{}` backup call received", name);
+        next = None;
+        if backup.is_none() {
+            // THE INITIALIZATION IS RETRIED ON THE NEXT PASS, WHICH THEN MAKES THE CALL
+            call_on_init = true;
+            continue;
+        }
+
+        // OVERLAP HANDLING
+        let (overlap, min_interval) = {
+            let config = config.lock().await;
+            (config.overlap(&name), config.min_interval(&name))
+        };
+        if let Some(last_start) = last_start {
+            if last_start.elapsed() < min_interval {
+                warn!(
+                    "Skipping the `{}` backup call, the previous one has started less than {}s ago",
+                    name,
+                    min_interval.as_secs()
+                );
+                continue;
+            }
+        }
+        if running.is_some() {
+            match overlap {
+                OverlapPolicy::Skip => {
+                    warn!(
+                        "Skipping the `{}` backup call, the previous one is still running",
+                        name
+                    );
+                    continue;
+                }
+                OverlapPolicy::QueueOne => {
+                    if queued {
+                        warn!(
+                            "Skipping the `{}` backup call, one is already queued behind the running one",
+                            name
+                        );
+                    } else {
+                        info!("Queueing the `{}` backup call behind the running one", name);
+                        queued = true;
+                    }
+                    continue;
+                }
+                OverlapPolicy::CancelAndRestart => {
+                    warn!(
+                        "Cancelling the running `{}` backup to start the new call",
+                        name
+                    );
+                    running.take().unwrap().cancel().await;
+                }
+            }
+        }
+
+        if let Some(backup) = backup.clone() {
+            trace!("Calling `backup_changes` of `{}`", name);
+            running = Some(Run::start(section.prefix, async move {
+                backup.backup_changes().await
+            }));
+            last_start = Some(Instant::now());
+        }
+    }
+
+    // SHUTDOWN
+    let timeout = config.lock().await.shutdown_timeout();
+    wind_down::<B>(&name, &state, running, timeout).await
+}
+
+/// Call of a source, kept across the passes of its routine until it is made
+///
+/// The reloads of the config and the finished runs interrupt the wait for the call,
+/// so it must not be rescheduled after them, or a call, deferred past its scheduled time
+/// by the jitter or a blackout, would be lost.
+struct NextCall {
+    /// Time, for which the call is scheduled
+    scheduled: DateTime<Utc>,
+    /// Cron string, which has scheduled the call, `None` for the calls due at once
+    cron: Option<String>,
+    /// Timing, by which the call is deferred
+    timing: Timing,
+    /// Time, at which the call is made
+    due: DateTime<Utc>,
+}
+
+impl NextCall {
+    fn new(scheduled: DateTime<Utc>, cron: Option<String>, timing: &Timing) -> Self {
+        Self {
+            scheduled,
+            cron,
+            timing: timing.clone(),
+            due: defer_call(scheduled, timing),
+        }
+    }
+
+    /// Call, due at once, such as a catch-up or a queued one
+    fn at_once(now: DateTime<Utc>, timing: &Timing) -> Self {
+        Self::new(now, None, timing)
+    }
+
+    /// Time, at which the `next` call is made, scheduling it by `cron` after `now` if there is none
+    ///
+    /// A call, scheduled by another cron string or in another time zone, is rescheduled,
+    /// and the one, deferred by other jitter or blackouts, is deferred anew from its scheduled time.
+    /// Returns `None` if the schedule has no more calls.
+    fn due(
+        next: &mut Option<NextCall>,
+        cron: &str,
+        timing: &Timing,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if let Some(call) = next.as_mut() {
+            if call.cron.as_ref().is_some_and(|call_cron| {
+                call_cron != cron || call.timing.timezone != timing.timezone
+            }) {
+                *next = None;
+            } else if call.timing != *timing {
+                *call = NextCall::new(call.scheduled, call.cron.take(), timing);
+            }
+        }
+        if next.is_none() {
+            *next = next_call(cron, timing.timezone, now)
+                .map(|scheduled| NextCall::new(scheduled, Some(cron.into()), timing));
+        }
+        next.as_ref().map(|call| call.due)
+    }
+}
+
+/// Gives the `running` backup of the source `name` the `timeout` to finish,
+/// returning whether it has finished in time
+async fn wind_down<B: Backup>(
+    name: &str,
+    state: &Lock<State>,
+    running: Option<Run<error::Result<BackupReport>>>,
+    timeout: Duration,
+) -> bool {
+    let run = match running {
+        Some(run) => run,
+        None => return true,
+    };
+    info!(
+        "Waiting up to {}s for the running `{}` backup to finish",
+        timeout.as_secs(),
+        name
+    );
+    let started_at = run.started_at;
+    match run.finish_within(timeout, name).await {
+        Some(result) => {
+            finish_run::<B>(name, state, started_at, result).await;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Backup, running in the background
+struct Run<T> {
+    handle: JoinHandle<T>,
+    started_at: DateTime<Utc>,
+    /// Directory, where the backup writes its snapshot
+    prefix: String,
+    /// Snapshots, which had existed under `prefix` before the backup started
+    snapshots_before: HashSet<PathBuf>,
+}
+
+impl<T: Send + 'static> Run<T> {
+    fn start(prefix: String, backup: impl Future<Output = T> + Send + 'static) -> Self {
+        Self {
+            snapshots_before: snapshots(&prefix),
+            started_at: Utc::now(),
+            handle: tokio::spawn(backup),
+            prefix,
+        }
+    }
+
+    /// Waits for the backup to finish, cancelling it if it does not within `timeout`
+    async fn finish_within(
+        mut self,
+        timeout: Duration,
+        name: &str,
+    ) -> Option<Result<T, JoinError>> {
+        match tokio::time::timeout(timeout, &mut self.handle).await {
+            Ok(result) => Some(result),
+            Err(_) => {
+                warn!(
+                    "The `{}` backup has not finished within {}s, cancelling it",
+                    name,
+                    timeout.as_secs()
+                );
+                self.cancel().await;
+                None
+            }
+        }
+    }
+
+    /// Stops the backup, discarding the snapshots it has not completed (see [`BackupReport::finish`])
+    async fn cancel(self) {
+        self.handle.abort();
+        // THE TASK MAY STILL BE RUNNING UNTIL ITS NEXT AWAIT POINT
+        let _ = self.handle.await;
+        for snapshot in snapshots(&self.prefix).difference(&self.snapshots_before) {
+            if snapshot.join("report.json").exists() {
+                continue;
+            }
+            match std::fs::remove_dir_all(snapshot) {
+                Ok(()) => warn!("Discarded the incomplete snapshot `{}`", snapshot.display()),
+                Err(err) => error!(
+                    "Failed to discard the incomplete snapshot `{}`: {}",
+                    snapshot.display(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// Snapshot directories under `prefix`
+fn snapshots(prefix: &str) -> HashSet<PathBuf> {
+    std::fs::read_dir(prefix)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reports the result of a finished background backup, and records its run
+async fn finish_run<B: Backup>(
+    name: &str,
+    state: &Lock<State>,
+    started_at: DateTime<Utc>,
+    result: Result<error::Result<BackupReport>, JoinError>,
+) {
+    match result {
+        Ok(Ok(report)) => {
+            report.log();
+            trace!("Finished `backup_changes` of `{}`", name);
+        }
+        Ok(Err(err)) => error!("{} backup `{}` has failed: {}", B::NAME, name, err),
+        Err(err) => error!("{} backup `{}` has crashed: {}", B::NAME, name, err),
+    }
+    record_run(state, name, started_at).await;
+}
+
+/// Initializes the backup of the source `name` of type `B`, and backs its changes up
+///
+/// Returns the report of the initial backup, if one has been done, followed by the one of the changes.
+/// Once `stop` is set, the backup is stopped like a scheduled one.
+async fn backup_once<B: Backup>(
+    name: String,
+    config: Lock<Config>,
+    state: Lock<State>,
+    mut stop: Stop,
+) -> Option<Vec<BackupReport>> {
+    let prefix = B::section(&*config.lock().await, &name)?.prefix;
+    if *stop.borrow() {
+        warn!("Skipping the `{}` backup, the program is stopping", name);
+        return None;
+    }
+    let mut run = Run::start(prefix, {
+        let (name, config, state) = (name.clone(), config.clone(), state.clone());
+        async move {
+            let started_at = Utc::now();
+            let (backup, initial_report) = B::new(name.clone(), config, state.clone()).await?;
+            let mut reports = Vec::from_iter(initial_report);
+            reports.iter().for_each(BackupReport::log);
+            let result = backup.backup_changes().await;
+            record_run(&state, &name, started_at).await;
+            result.map(|report| {
+                report.log();
+                reports.push(report);
+                reports
+            })
+        }
+    });
+    let result = tokio::select! {
+        result = &mut run.handle => Some(result),
+        _ = stopped(&mut stop) => {
+            let timeout = config.lock().await.shutdown_timeout();
+            run.finish_within(timeout, &name).await
+        }
+    };
+    match result? {
+        Ok(Ok(reports)) => Some(reports),
+        Ok(Err(err)) => {
+            error!("{} backup `{}` has failed: {}", B::NAME, name, err);
+            None
+        }
+        Err(err) => {
+            error!("{} backup `{}` has crashed: {}", B::NAME, name, err);
+            None
+        }
+    }
+}
+
+/// Whether the source `name` has to be backed up right on start, instead of waiting for `cron`
+async fn catch_up(
+    name: &str,
+    cron: &str,
+    timezone: Timezone,
+    config: &Lock<Config>,
+    state: &Lock<State>,
+) -> bool {
+    match config.lock().await.catch_up(name) {
+        CatchUpPolicy::Skip => false,
+        CatchUpPolicy::RunOnStart => true,
+        CatchUpPolicy::RunOnce => {
+            // A SOURCE, WHICH HAS NEVER RUN, STARTS WITH THE INITIAL BACKUP ANYWAY
+            let state = state.lock().await;
+            state
+                .last_runs
+                .get(name)
+                .and_then(|last_run| DateTime::parse_from_rfc3339(last_run).ok())
+                .map(|last_run| missed_call(cron, timezone, last_run.with_timezone(&Utc)))
+                .unwrap_or(false)
+        }
+    }
+}
+
+/// Persists the start time of the last run of the source `name`
+async fn record_run(state: &Lock<State>, name: &str, started_at: DateTime<Utc>) {
+    let mut state = state.lock().await;
+    state
+        .last_runs
+        .insert(name.to_owned(), started_at.to_rfc3339());
+    if let Err(err) = state.write() {
+        error!("Failed to save the last run of `{}`: {}", name, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Blackout;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn working_hours() -> Timing {
+        Timing {
+            blackouts: vec![Blackout {
+                start: "09:00".into(),
+                end: "18:00".into(),
+                days: Vec::new(),
+            }],
+            ..Timing::default()
+        }
+    }
+
+    #[test]
+    fn deferred_call_is_kept_across_the_reloads_and_the_finished_runs() {
+        let timing = working_hours();
+        let mut next = None;
+        let due = NextCall::due(
+            &mut next,
+            "0 0 10 * * *",
+            &timing,
+            utc("2024-07-01T08:00:00Z"),
+        );
+        assert_eq!(due, Some(utc("2024-07-01T18:00:00Z")));
+        // A RELOAD OR A FINISHED RUN AT NOON, PAST THE SCHEDULED TIME, BUT BEFORE THE CALL
+        let due = NextCall::due(
+            &mut next,
+            "0 0 10 * * *",
+            &timing,
+            utc("2024-07-01T12:00:00Z"),
+        );
+        assert_eq!(due, Some(utc("2024-07-01T18:00:00Z")));
+    }
+
+    #[test]
+    fn deferred_call_is_deferred_anew_by_the_reloaded_blackouts() {
+        let mut next = None;
+        NextCall::due(
+            &mut next,
+            "0 0 10 * * *",
+            &working_hours(),
+            utc("2024-07-01T08:00:00Z"),
+        );
+        let due = NextCall::due(
+            &mut next,
+            "0 0 10 * * *",
+            &Timing::default(),
+            utc("2024-07-01T12:00:00Z"),
+        );
+        assert_eq!(due, Some(utc("2024-07-01T10:00:00Z")));
+    }
+
+    #[test]
+    fn call_is_rescheduled_by_the_reloaded_cron() {
+        let timing = Timing::default();
+        let mut next = None;
+        NextCall::due(
+            &mut next,
+            "0 0 10 * * *",
+            &timing,
+            utc("2024-07-01T08:00:00Z"),
+        );
+        let due = NextCall::due(
+            &mut next,
+            "0 0 20 * * *",
+            &timing,
+            utc("2024-07-01T09:00:00Z"),
+        );
+        assert_eq!(due, Some(utc("2024-07-01T20:00:00Z")));
+    }
+
+    #[test]
+    fn call_due_at_once_is_kept_across_the_cron_changes() {
+        let timing = working_hours();
+        let mut next = Some(NextCall::at_once(utc("2024-07-01T12:00:00Z"), &timing));
+        let due = NextCall::due(
+            &mut next,
+            "0 0 20 * * *",
+            &timing,
+            utc("2024-07-01T13:00:00Z"),
+        );
+        assert_eq!(due, Some(utc("2024-07-01T18:00:00Z")));
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::*;
+use tokio::sync::watch;
+
+use crate::{config::Config, util::Lock};
+
+/// Interval between the checks of the config file modification time
+static FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Receiver, notified every time the config has been reloaded
+pub type Reloads = watch::Receiver<()>;
+
+/// Reloads the config at `path` on SIGHUP, and on file modification if `watch_file` is set
+///
+/// An invalid config is rejected as a whole, and the current one is kept.
+/// Every successful reload is announced through `reloads`.
+pub async fn watch_config(
+    path: PathBuf,
+    config: Lock<Config>,
+    reloads: watch::Sender<()>,
+    watch_file: bool,
+) {
+    let mut hangup = Hangup::new();
+    let mut poll = tokio::time::interval(FILE_POLL_INTERVAL);
+    let mut modified = modified_time(&path);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!("SIGHUP received, reloading the configuration."),
+            _ = poll.tick(), if watch_file => {
+                let current = modified_time(&path);
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                info!("The configuration file has changed, reloading it.");
+            }
+        }
+
+        match Config::read(&path) {
+            Ok(new_config) => {
+                let problems = new_config.validate();
+                if problems.is_empty() {
+                    *config.lock().await = new_config;
+                    info!("Configuration reloaded from `{}`.", path.display());
+                    let _ = reloads.send(());
+                } else {
+                    error!("The new configuration is invalid, keeping the current one:");
+                    for problem in problems {
+                        error!("  {}", problem);
+                    }
+                }
+            }
+            Err(err) => error!(
+                "Failed to reload the configuration, keeping the current one: {}",
+                err
+            ),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// SIGHUP listener, which never fires on the platforms without signals
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        Self(
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .expect("Failed to listen for SIGHUP"),
+        )
+    }
+
+    async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Self
+    }
+
+    async fn recv(&mut self) {
+        futures::future::pending::<()>().await
+    }
+}
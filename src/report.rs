@@ -0,0 +1,119 @@
+use std::fmt::Display;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Outcome of backing a single item (file, board, ...) up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemOutcome {
+    /// The item was written to the snapshot, taking the given number of bytes
+    Written(u64),
+    /// The item cannot be backed up, and was deliberately left out
+    Skipped,
+}
+
+/// An item, which could not be backed up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemFailure {
+    pub item: String,
+    pub reason: String,
+}
+
+/// Record of what a single backup run has done
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupReport {
+    pub source: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    /// Directory of the snapshot, if the run has produced one
+    pub snapshot: Option<String>,
+    pub attempted: usize,
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: Vec<ItemFailure>,
+    pub bytes_written: u64,
+}
+
+impl BackupReport {
+    /// Starts the report of a run of the `source`
+    pub fn new(source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            started_at: Utc::now().to_rfc3339(),
+            finished_at: None,
+            snapshot: None,
+            attempted: 0,
+            succeeded: 0,
+            skipped: 0,
+            failed: Vec::new(),
+            bytes_written: 0,
+        }
+    }
+
+    /// Records the outcome of backing the `item` up
+    pub fn record(&mut self, item: impl Display, outcome: &Result<ItemOutcome>) {
+        self.attempted += 1;
+        match outcome {
+            Ok(ItemOutcome::Written(bytes)) => {
+                self.succeeded += 1;
+                self.bytes_written += bytes;
+            }
+            Ok(ItemOutcome::Skipped) => self.skipped += 1,
+            Err(err) => self.failed.push(ItemFailure {
+                item: item.to_string(),
+                reason: err.to_string(),
+            }),
+        }
+    }
+
+    /// Whether every attempted item was either backed up or skipped
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Marks the run as finished, writing the report as `report.json` next to the snapshot
+    ///
+    /// Only then is the snapshot complete, so a source must not advance its state before it:
+    /// the changes of an incomplete snapshot, discarded on shutdown, are picked up by the next run.
+    pub fn finish(&mut self) -> Result<()> {
+        self.finished_at = Some(Utc::now().to_rfc3339());
+        if let Some(snapshot) = self.snapshot.as_ref() {
+            let path = format!("{}/report.json", snapshot);
+            std::fs::write(
+                &path,
+                serde_json::to_string_pretty(self).expect("Backup report is serializable"),
+            )
+            .map_err(|err| Error::filesystem(&path, err))?;
+        }
+        Ok(())
+    }
+
+    /// Logs the summary and every failure of the run
+    pub fn log(&self) {
+        log::info!("{}", self);
+        for ItemFailure { item, reason } in &self.failed {
+            log::error!("{}: failed to back {} up: {}", self.source, item, reason);
+        }
+    }
+}
+
+impl Display for BackupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} attempted, {} succeeded, {} skipped, {} failed, {} byte(s) written",
+            self.source,
+            self.attempted,
+            self.succeeded,
+            self.skipped,
+            self.failed.len(),
+            self.bytes_written
+        )?;
+        if let Some(snapshot) = self.snapshot.as_ref() {
+            write!(f, " to `{}`", snapshot)?;
+        }
+        Ok(())
+    }
+}
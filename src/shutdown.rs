@@ -0,0 +1,32 @@
+use tokio::sync::watch;
+
+/// Receiver, set to `true` once the program has been asked to stop
+pub type Stop = watch::Receiver<bool>;
+
+/// Resolves once the program has been asked to stop, at once if it already has
+pub async fn stopped(stop: &mut Stop) {
+    // THE SENDER LIVES AS LONG AS THE SCHEDULER, SO AN ERROR MEANS THE PROGRAM IS EXITING ANYWAY
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+/// Resolves on SIGTERM or SIGINT, returning the name of the received signal
+#[cfg(unix)]
+pub async fn requested() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    }
+}
+
+/// Resolves on Ctrl+C, returning the name of the received signal
+#[cfg(not(unix))]
+pub async fn requested() -> &'static str {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to listen for Ctrl+C");
+    "Ctrl+C"
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+
+/// Synchronization state of the sources, persisted between runs
+///
+/// Unlike the config, the state is only ever written by the program itself.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    /// Where the state is persisted
+    #[serde(skip)]
+    path: PathBuf,
+    /// Start time of the last run of every source, by name
+    #[serde(default)]
+    pub last_runs: BTreeMap<String, String>,
+    /// State of every source, by name, in the format of its type
+    #[serde(default)]
+    sources: BTreeMap<String, Value>,
+}
+
+impl State {
+    /// Path of the state file, kept as `state.json` next to the config at `config_path`
+    pub fn path_for(config_path: &Path) -> PathBuf {
+        config_path.with_file_name("state.json")
+    }
+
+    /// Reads the state from `path`, starting from a blank one if there is none
+    pub fn init(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let invalid = |err: serde_json::Error| {
+            Error::filesystem(
+                &path,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+            )
+        };
+        let mut state: Self = match std::fs::read_to_string(&path) {
+            Ok(source) => serde_json::from_str(&source).map_err(invalid)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("No state file found, starting from a blank state.");
+                Self::default()
+            }
+            Err(err) => return Err(Error::filesystem(&path, err)),
+        };
+        state.path = path;
+        Ok(state)
+    }
+
+    /// Path of the file `file` of the source `name`, kept next to the state, e.g. its tokens
+    pub fn source_file(&self, name: &str, file: &str) -> PathBuf {
+        self.path.with_file_name(format!("{}-{}", name, file))
+    }
+
+    /// State of the source `name`, blank if it has not been backed up yet
+    pub fn source<T: DeserializeOwned + Default>(&self, name: &str) -> Result<T> {
+        match self.sources.get(name) {
+            Some(state) => T::deserialize(state).map_err(|err| {
+                Error::filesystem(
+                    &self.path,
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("state of the source `{}`: {}", name, err),
+                    ),
+                )
+            }),
+            None => Ok(T::default()),
+        }
+    }
+
+    /// Replaces the state of the source `name`, to be persisted by [`State::write`]
+    pub fn set_source<T: Serialize>(&mut self, name: &str, state: &T) {
+        self.sources.insert(
+            name.into(),
+            serde_json::to_value(state).expect("Source state is serializable"),
+        );
+    }
+
+    /// Atomically writes this state to the file it was read from
+    ///
+    /// The state is written to a temporary file first, which then replaces the old one,
+    /// so a crash never leaves a truncated state behind.
+    pub fn write(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(
+            &tmp_path,
+            serde_json::to_string_pretty(self).expect("State is serializable"),
+        )
+        .map_err(|err| Error::filesystem(&tmp_path, err))?;
+        std::fs::rename(&tmp_path, &self.path).map_err(|err| Error::filesystem(&self.path, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn missing_source_state_is_blank() {
+        #[derive(Deserialize, Default, PartialEq, Debug)]
+        struct SourceState {
+            page_token: Option<String>,
+        }
+
+        let mut state = State::default();
+        assert_eq!(
+            state.source::<SourceState>("drive").unwrap(),
+            SourceState::default()
+        );
+        state
+            .sources
+            .insert("drive".into(), json!({"page_token": "1"}));
+        assert_eq!(
+            state
+                .source::<SourceState>("drive")
+                .unwrap()
+                .page_token
+                .as_deref(),
+            Some("1")
+        );
+    }
+}
@@ -2,13 +2,33 @@ use chrono::Utc;
 use tokio::sync::Mutex;
 
 use crate::{
-    config::Config,
-    util::{utc_to_string, Backup, Lock},
+    config::{check_credential, Config, SourceSettings, TrelloConfig},
+    error::{self, Error},
+    report::{BackupReport, ItemOutcome},
+    state::State,
+    util::{
+        parse_retry_after, retry, utc_to_string, Backup, Failure, Lock, Retryable, SourceSection,
+    },
 };
-use std::{pin::Pin, sync::Arc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Display, pin::Pin, sync::Arc};
 
 pub struct TrelloBackup {
+    /// Name of the source in the config
+    name: String,
     config: Lock<Config>,
+    state: Lock<State>,
+}
+
+/// Synchronization state of a Trello source
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TrelloState {
+    pub last_sync_time: Option<String>,
+    /// Id of the latest action of each board, as of the last backup
+    #[serde(default)]
+    pub last_action_ids: BTreeMap<String, String>,
+    pub last_snapshot: Option<String>,
 }
 
 static EXPORT_PARAMETERS: &str = "fields=all\
@@ -30,60 +50,290 @@ static EXPORT_PARAMETERS: &str = "fields=all\
 
 #[async_trait::async_trait]
 impl Backup for TrelloBackup {
-    async fn new(config: Pin<Arc<Mutex<Config>>>) -> Self {
-        Self { config }
+    const NAME: &'static str = "Trello";
+    const KIND: &'static str = "trello";
+    const SECRET_FIELDS: &'static [&'static str] = &["api_key", "personal_token"];
+
+    fn section(config: &Config, name: &str) -> Option<SourceSection> {
+        settings(config, name).map(|trello| SourceSection {
+            backup_cron: trello.backup_cron.clone(),
+            prefix: trello.prefix.clone(),
+            // THE KEY AND THE TOKEN ARE READ ON EVERY RUN
+            credentials: Vec::new(),
+        })
+    }
+
+    fn validate(config: &Config, name: &str, problem: &mut dyn FnMut(&str, String)) {
+        let trello = match settings(config, name) {
+            Some(trello) => trello,
+            None => return,
+        };
+        let placeholder = TrelloConfig::default();
+        if let Err(message) = check_credential(&trello.api_key, &placeholder.api_key) {
+            problem("api_key", message);
+        }
+        if let Err(message) = check_credential(&trello.personal_token, &placeholder.personal_token)
+        {
+            problem("personal_token", message);
+        }
+        if trello.board_ids.is_empty() {
+            problem("board_ids", "must list at least one board".into());
+        }
+        for (i, board_id) in trello.board_ids.iter().enumerate() {
+            if placeholder.board_ids.contains(board_id) {
+                problem(
+                    &format!("board_ids[{}]", i),
+                    format!("`{}` is a placeholder, put a real board id here", board_id),
+                );
+            }
+        }
+    }
+
+    fn template() -> String {
+        let defaults = TrelloConfig::default();
+        format!(
+            "  - name: trello
+    type: {}
+    # Schedule of the backups: sec min hour day-of-month month day-of-week
+    backup_cron: {:?}
+    # Credentials from https://trello.com/app-key, each may also be given as
+    # a `${{VARIABLE}}` reference or read from a file with e.g. `api_key_file`
+    api_key: {:?}
+    personal_token: {:?}
+    # Directory, where the snapshots are written
+    prefix: {:?}
+    board_ids:
+{}",
+            Self::KIND,
+            defaults.backup_cron,
+            defaults.api_key,
+            defaults.personal_token,
+            defaults.prefix,
+            defaults
+                .board_ids
+                .iter()
+                .map(|board_id| format!("      - {:?}\n", board_id))
+                .collect::<String>(),
+        )
+    }
+
+    fn status(state: &State, name: &str) -> Vec<String> {
+        let state = match state.source::<TrelloState>(name) {
+            Ok(state) => state,
+            Err(err) => return vec![err.to_string()],
+        };
+        let mut status = vec![
+            format!(
+                "last sync: {}",
+                state.last_sync_time.as_deref().unwrap_or("never")
+            ),
+            format!(
+                "last snapshot: {}",
+                state.last_snapshot.as_deref().unwrap_or("none")
+            ),
+        ];
+        status.extend(state.last_action_ids.iter().map(|(board_id, action_id)| {
+            format!("board {}: latest action {}", board_id, action_id)
+        }));
+        status
+    }
+
+    async fn new(
+        name: String,
+        config: Pin<Arc<Mutex<Config>>>,
+        state: Lock<State>,
+    ) -> error::Result<(Self, Option<BackupReport>)> {
+        Ok((
+            Self {
+                name,
+                config,
+                state,
+            },
+            None,
+        ))
     }
 
-    async fn backup_changes(&self) {
+    async fn backup_changes(&self) -> error::Result<BackupReport> {
+        let mut report = BackupReport::new(format!("{} `{}`", Self::NAME, self.name));
+
         // DESTRUCTURING CONFIG
-        let (api_key, token, boards, prefix) = self
-            .config
-            .lock()
-            .await
-            .trello
-            .as_ref()
-            .map(|conf| {
-                (
-                    conf.api_key.clone(),
-                    conf.personal_token.clone(),
-                    conf.board_ids.clone(),
-                    conf.prefix.clone(),
-                )
-            })
-            .unwrap();
+        let (api_key, token, boards, prefix, retry_policy) = {
+            let config = self.config.lock().await;
+            let conf = settings(&config, &self.name).ok_or_else(|| {
+                Error::Config(format!("there is no Trello source `{}`", self.name))
+            })?;
+            (
+                conf.api_key.clone(),
+                conf.personal_token.clone(),
+                conf.board_ids.clone(),
+                conf.prefix.clone(),
+                config.retry.clone(),
+            )
+        };
 
         // CREATING DIRECTORY
         let path = format!("{}/{}", prefix, utc_to_string(Utc::now()));
-        std::fs::create_dir_all(&path).unwrap();
+        std::fs::create_dir_all(&path).map_err(|err| Error::filesystem(&path, err))?;
 
         // DOWNLOADING BOARDS
-        futures::future::join_all(
+        let authorization = format!(
+            "OAuth oauth_consumer_key=\"{}\", oauth_token=\"{}\"",
+            api_key, token
+        );
+        let results = futures::future::join_all(
             boards
                 .into_iter()
                 .map(|board_id| {
                     (
                         board_id.clone(),
                         path.clone(),
-                        reqwest::get(format!(
-                            "https://api.trello.com/1/boards/{}?key={}&token={}&{}",
-                            board_id, api_key, token, EXPORT_PARAMETERS,
-                        )),
+                        format!(
+                            "https://api.trello.com/1/boards/{}?{}",
+                            board_id, EXPORT_PARAMETERS,
+                        ),
                     )
                 })
-                .map(|(board_id, path, res)| async move {
-                    let res = res.await.unwrap();
-                    if !res.status().is_success() {
-                        log::error!(
-                            "CRITICAL: Trello backup has returned status code {}",
-                            res.status()
-                        );
-                        log::error!("{:#?}", res);
-                        panic!("Failed to perform Trello board backup. Details are written to log.")
+                .map(|(board_id, path, url)| {
+                    let retry_policy = &retry_policy;
+                    let authorization = &authorization;
+                    async move {
+                        let result = async {
+                            let res = retry(
+                                retry_policy,
+                                format!("download trello board {}", board_id),
+                                || get_board(&url, authorization),
+                            )
+                            .await?;
+                            let board_path = format!("{}/{}", path, board_id);
+                            let bytes = res.bytes().await?;
+                            std::fs::write(&board_path, &bytes)
+                                .map_err(|err| Error::filesystem(&board_path, err))?;
+                            Ok((
+                                ItemOutcome::Written(bytes.len() as u64),
+                                last_action_id(&bytes),
+                            ))
+                        }
+                        .await;
+                        (board_id, result)
                     }
-                    std::fs::write(format!("{}/{}", path, board_id), res.bytes().await.unwrap())
-                        .unwrap();
                 }),
         )
         .await;
+
+        // A single failed board should not prevent the others from being backed up
+        let mut last_action_ids = Vec::new();
+        for (board_id, result) in results {
+            let result = result.map(|(outcome, action_id)| {
+                if let Some(action_id) = action_id {
+                    last_action_ids.push((board_id.clone(), action_id));
+                }
+                outcome
+            });
+            report.record(format!("board {}", board_id), &result);
+        }
+        report.snapshot = Some(path);
+        report.finish()?;
+
+        // PERSISTING STATE
+        let mut state = self.state.lock().await;
+        let mut trello_state = state.source::<TrelloState>(&self.name)?;
+        trello_state.last_action_ids.extend(last_action_ids);
+        trello_state.last_sync_time = Some(Utc::now().to_rfc3339());
+        trello_state.last_snapshot = report.snapshot.clone();
+        state.set_source(&self.name, &trello_state);
+        state.write()?;
+        Ok(report)
+    }
+}
+
+/// Settings of the Trello source `name`
+fn settings<'a>(config: &'a Config, name: &str) -> Option<&'a TrelloConfig> {
+    config
+        .sources
+        .iter()
+        .find_map(|source| match &source.settings {
+            SourceSettings::Trello(trello) if source.name == name => Some(trello),
+            _ => None,
+        })
+}
+
+/// Extracts the id of the latest action from an exported board
+///
+/// Trello lists the actions newest first.
+fn last_action_id(board: &[u8]) -> Option<String> {
+    serde_json::from_slice::<serde_json::Value>(board)
+        .ok()?
+        .get("actions")?
+        .get(0)?
+        .get("id")?
+        .as_str()
+        .map(String::from)
+}
+
+/// Requests a board export, authorized by the `Authorization` header
+///
+/// The credentials are kept out of the URL, as it ends up in the error messages.
+async fn get_board(url: &str, authorization: &str) -> Result<reqwest::Response, TrelloError> {
+    let res = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::AUTHORIZATION, authorization)
+        .send()
+        .await
+        .map_err(|err| TrelloError::Network(err.without_url()))?;
+    if res.status().is_success() {
+        Ok(res)
+    } else {
+        Err(TrelloError::Status(res))
+    }
+}
+
+/// Failed Trello API call
+enum TrelloError {
+    /// The request could not be sent, or the response could not be received
+    Network(reqwest::Error),
+    /// Trello has responded with a non-success status code
+    Status(reqwest::Response),
+}
+
+impl Display for TrelloError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrelloError::Network(err) => err.fmt(f),
+            TrelloError::Status(res) => write!(f, "status code {}", res.status()),
+        }
+    }
+}
+
+impl From<TrelloError> for Error {
+    fn from(err: TrelloError) -> Self {
+        match err {
+            TrelloError::Network(err) => err.into(),
+            TrelloError::Status(res) if res.status() == StatusCode::UNAUTHORIZED => {
+                Error::Auth(format!("Trello has returned status code {}", res.status()))
+            }
+            TrelloError::Status(res) => {
+                Error::Api(format!("Trello has returned status code {}", res.status()))
+            }
+        }
+    }
+}
+
+impl Retryable for TrelloError {
+    fn classify(&self) -> Failure {
+        match self {
+            TrelloError::Network(_) => Failure::Transient(None),
+            TrelloError::Status(res)
+                if res.status() == StatusCode::TOO_MANY_REQUESTS
+                    || res.status().is_server_error() =>
+            {
+                Failure::Transient(
+                    res.headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(parse_retry_after),
+                )
+            }
+            TrelloError::Status(_) => Failure::Permanent,
+        }
     }
 }
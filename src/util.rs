@@ -1,12 +1,20 @@
-use std::{ops::Sub, pin::Pin, str::FromStr, sync::Arc};
+use std::{
+    fmt::Display, future::Future, ops::Sub, pin::Pin, str::FromStr, sync::Arc, time::Duration,
+};
 
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
 use cron::Schedule;
 use futures_timer::Delay;
+use rand::Rng;
 use tokio::sync::Mutex;
 
-use crate::config::Config;
+use crate::{
+    config::{Config, RetryConfig, Timezone, Timing},
+    error,
+    report::BackupReport,
+    state::State,
+};
 
 /// Defines the datetime printed format
 #[inline(always)]
@@ -14,30 +22,304 @@ pub fn utc_to_string(date: DateTime<Utc>) -> String {
     date.format("%F-%H-%M-%S").to_string()
 }
 
-/// Creates a delayed future, given a cron string.
+/// Time, at which a call due at `call` is made according to `timing`
+///
+/// A random delay of up to `timing.jitter` is added to the call,
+/// and a call, falling into one of `timing.blackouts`, is deferred until the blackout ends.
+pub fn defer_call(call: DateTime<Utc>, timing: &Timing) -> DateTime<Utc> {
+    let mut call = call + jitter(timing.jitter);
+    // THE BLACKOUTS MAY OVERLAP, SO THE DEFERRED CALL IS CHECKED AGAIN
+    for _ in 0..=timing.blackouts.len() {
+        let wall_clock = to_wall_clock(timing.timezone, call);
+        let end = match timing
+            .blackouts
+            .iter()
+            .find_map(|blackout| blackout.end_after(wall_clock))
+        {
+            Some(end) => end,
+            None => break,
+        };
+        call = from_wall_clock(timing.timezone, end) + jitter(timing.jitter);
+        log::info!(
+            "The call at {} falls into a blackout, deferring it to {}",
+            wall_clock.format("%F %T"),
+            to_wall_clock(timing.timezone, call).format("%F %T")
+        );
+    }
+    call
+}
+
+/// Creates a future, delayed until `time`
+pub async fn await_time(time: DateTime<Utc>) {
+    Delay::new(time.sub(Utc::now()).to_std().unwrap_or_default()).await
+}
+
+/// Random delay of up to `max`
+fn jitter(max: Duration) -> chrono::Duration {
+    chrono::Duration::milliseconds(rand::thread_rng().gen_range(0..=max.as_millis() as i64))
+}
+
+/// Whether a call, scheduled by the cron string in `timezone`, was due between `last_run` and now
 ///
 /// Panics if the cron string is invalid.
-pub async fn await_next_call(cron: impl AsRef<str>) {
-    Delay::new(
-        Schedule::from_str(cron.as_ref())
-            .unwrap()
-            .upcoming(chrono::Utc)
-            .next()
-            .unwrap()
-            .sub(chrono::Utc::now())
-            .to_std()
-            .unwrap(),
-    )
-    .await
+pub fn missed_call(cron: impl AsRef<str>, timezone: Timezone, last_run: DateTime<Utc>) -> bool {
+    next_call(cron, timezone, last_run)
+        .map(|next_call| next_call <= Utc::now())
+        .unwrap_or(false)
+}
+
+/// First call, scheduled by the cron string in `timezone` after `after`
+///
+/// The schedule is evaluated on the wall clock rather than in the time zone itself,
+/// so that the DST transitions neither skip nor repeat the calls (see [`from_wall_clock`]).
+/// Returns `None` if the schedule has no more calls, and panics if the cron string is invalid.
+pub fn next_call(
+    cron: impl AsRef<str>,
+    timezone: Timezone,
+    after: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let wall_clock = Utc.from_utc_datetime(&to_wall_clock(timezone, after));
+    Schedule::from_str(cron.as_ref())
+        .unwrap()
+        .after(&wall_clock)
+        .map(|call| from_wall_clock(timezone, call.naive_utc()))
+        .find(|call| *call > after)
+}
+
+/// Wall clock time of `timezone` at `time`
+fn to_wall_clock(timezone: Timezone, time: DateTime<Utc>) -> NaiveDateTime {
+    match timezone {
+        Timezone::Local => time.with_timezone(&Local).naive_local(),
+        Timezone::Named(timezone) => time.with_timezone(&timezone).naive_local(),
+    }
+}
+
+/// Time, when the wall clock of `timezone` shows `wall_clock`
+///
+/// A time, repeated in autumn, is taken on its first occurrence,
+/// and a time, skipped in spring, is taken with the offset before the transition.
+fn from_wall_clock(timezone: Timezone, wall_clock: NaiveDateTime) -> DateTime<Utc> {
+    match timezone {
+        Timezone::Local => from_wall_clock_in(&Local, wall_clock),
+        Timezone::Named(timezone) => from_wall_clock_in(&timezone, wall_clock),
+    }
+}
+
+fn from_wall_clock_in<Tz: TimeZone>(timezone: &Tz, wall_clock: NaiveDateTime) -> DateTime<Utc> {
+    match timezone.from_local_datetime(&wall_clock) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.with_timezone(&Utc),
+        LocalResult::None => {
+            let offset = timezone
+                .offset_from_utc_datetime(&(wall_clock - chrono::Duration::days(1)))
+                .fix();
+            (wall_clock - offset).and_utc()
+        }
+    }
 }
 
 pub type Lock<T> = Pin<Arc<Mutex<T>>>;
 
+/// Backup source, run by the scheduler once registered in [`crate::registry::source_types`]
 #[async_trait]
-pub trait Backup {
-    /// Constructs Backup object, given config
-    async fn new(config: Lock<Config>) -> Self;
+pub trait Backup: Sized + Send + Sync + 'static {
+    /// Name of the source type in the logs, e.g. `Google Drive`
+    const NAME: &'static str;
+    /// `type` of the source in the config, e.g. `google_drive`
+    const KIND: &'static str;
+    /// Fields of the settings, which may be given as an environment variable reference
+    /// or a `*_file` path
+    const SECRET_FIELDS: &'static [&'static str];
+
+    /// Settings, shared by every source, of the source `name` of this type
+    ///
+    /// Returns `None`, if there is no such source.
+    fn section(config: &Config, name: &str) -> Option<SourceSection>;
+
+    /// Checks the settings of the source `name`, which cannot be checked by parsing alone,
+    /// besides the ones of [`SourceSection`]
+    ///
+    /// Every problem is passed to `problem` along with its field, relative to the source.
+    fn validate(config: &Config, name: &str, problem: &mut dyn FnMut(&str, String));
+
+    /// Entry of a source of this type in the config template, as an item of `sources`,
+    /// with the default values filled in
+    fn template() -> String;
+
+    /// Human-readable synchronization state of the source `name`, one entry per line
+    fn status(state: &State, name: &str) -> Vec<String>;
+
+    /// Constructs Backup object of the source `name`, given config and the synchronization state
+    ///
+    /// A source may have to be backed up as a whole before its changes can be followed,
+    /// in which case the report of that initial backup is returned along with the object.
+    async fn new(
+        name: String,
+        config: Lock<Config>,
+        state: Lock<State>,
+    ) -> error::Result<(Self, Option<BackupReport>)>;
+
+    /// Backs the corresponding changes up, reporting what has been done
+    async fn backup_changes(&self) -> error::Result<BackupReport>;
+}
+
+/// Settings, which every source has in its config section
+pub struct SourceSection {
+    pub backup_cron: String,
+    /// Directory, where the snapshots are written
+    pub prefix: String,
+    /// Settings, which the backup object keeps from its construction
+    ///
+    /// The object is rebuilt on a reload only if these change, as the rest of the settings
+    /// is re-read on every run.
+    pub credentials: Vec<String>,
+}
+
+/// Classification of a failed API call, as seen by the retry policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Rate limits, server and network errors. The server may ask to wait before retrying.
+    Transient(Option<Duration>),
+    /// Errors which will not go away by retrying
+    Permanent,
+}
+
+/// Errors, which can be classified by the retry policy
+pub trait Retryable {
+    fn classify(&self) -> Failure;
+}
+
+/// Parses the `Retry-After` header value, given either in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse()
+        .map(Duration::from_secs)
+        .ok()
+        .or_else(|| {
+            DateTime::parse_from_rfc2822(value.trim())
+                .ok()
+                .and_then(|date| date.with_timezone(&Utc).sub(Utc::now()).to_std().ok())
+        })
+}
+
+/// Performs the `call`, retrying it with exponential backoff on transient failures
+///
+/// The delay doubles with every attempt, starting from the base delay and capped by the
+/// maximum one, with a random jitter added on top. A delay requested by the server takes
+/// precedence over the computed one.
+pub async fn retry<T, E, Fut>(
+    policy: &RetryConfig,
+    what: impl Display,
+    mut call: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    E: Retryable + Display,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let err = match call().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let retry_after = match err.classify() {
+            Failure::Transient(retry_after) if attempt < max_attempts => retry_after,
+            _ => return Err(err),
+        };
+        let delay = retry_after.unwrap_or_else(|| {
+            let backoff = Duration::from_millis(policy.base_delay_ms)
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(Duration::from_millis(policy.max_delay_ms));
+            backoff + Duration::from_millis(rand::thread_rng().gen_range(0..=policy.jitter_ms))
+        });
+        log::warn!(
+            "Attempt {}/{} to {} has failed, retrying in {:?}: {}",
+            attempt,
+            max_attempts,
+            what,
+            delay,
+            err
+        );
+        Delay::new(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn wall_clock(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%F %T").unwrap()
+    }
+
+    #[test]
+    fn from_wall_clock_takes_the_offset_before_a_skipped_time() {
+        // BERLIN SKIPS 02:00-03:00 ON 2024-03-31, THE OFFSET BEFORE IT IS +01:00
+        assert_eq!(
+            from_wall_clock_in(&Berlin, wall_clock("2024-03-31 02:30:00")),
+            utc("2024-03-31T01:30:00Z")
+        );
+    }
+
+    #[test]
+    fn from_wall_clock_takes_the_first_occurrence_of_a_repeated_time() {
+        // BERLIN REPEATS 02:00-03:00 ON 2024-10-27, FIRST AT +02:00
+        assert_eq!(
+            from_wall_clock_in(&Berlin, wall_clock("2024-10-27 02:30:00")),
+            utc("2024-10-27T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn from_wall_clock_keeps_an_unambiguous_time() {
+        assert_eq!(
+            from_wall_clock_in(&Berlin, wall_clock("2024-07-01 12:00:00")),
+            utc("2024-07-01T10:00:00Z")
+        );
+    }
+
+    #[test]
+    fn next_call_is_not_skipped_by_the_spring_transition() {
+        let timezone = Timezone::Named(Berlin);
+        let call = next_call("0 30 2 * * *", timezone, utc("2024-03-30T12:00:00Z"));
+        assert_eq!(call, Some(utc("2024-03-31T01:30:00Z")));
+        let call = next_call("0 30 2 * * *", timezone, call.unwrap());
+        assert_eq!(call, Some(utc("2024-04-01T00:30:00Z")));
+    }
+
+    #[test]
+    fn next_call_is_not_repeated_by_the_autumn_transition() {
+        let timezone = Timezone::Named(Berlin);
+        let call = next_call("0 30 2 * * *", timezone, utc("2024-10-26T12:00:00Z"));
+        assert_eq!(call, Some(utc("2024-10-27T00:30:00Z")));
+        let call = next_call("0 30 2 * * *", timezone, call.unwrap());
+        assert_eq!(call, Some(utc("2024-10-28T01:30:00Z")));
+    }
+
+    #[test]
+    fn next_call_follows_the_wall_clock_of_the_timezone() {
+        let call = next_call(
+            "0 0 9 * * *",
+            Timezone::Named(Berlin),
+            utc("2024-07-01T00:00:00Z"),
+        );
+        assert_eq!(call, Some(utc("2024-07-01T07:00:00Z")));
+    }
 
-    /// Backs the corresponding changes up
-    async fn backup_changes(&self);
+    #[test]
+    fn missed_call_is_detected_since_the_last_run() {
+        let timezone = Timezone::Named(Berlin);
+        let week_ago = Utc::now() - chrono::Duration::days(7);
+        assert!(missed_call("0 30 2 * * *", timezone, week_ago));
+        assert!(!missed_call("0 0 0 1 1 *", timezone, Utc::now()));
+    }
 }